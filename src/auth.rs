@@ -0,0 +1,384 @@
+//! Logging in to Spotify and managing the credential cache.
+
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, Context, OptionExt},
+    Result,
+};
+use colored::Colorize;
+use futures_util::StreamExt;
+use librespot::{
+    core::{cache::Cache, config::SessionConfig, session::Session},
+    discovery::{Credentials, DeviceType, Discovery},
+    protocol::authentication::AuthenticationType,
+};
+
+use crate::{
+    config::{AccountConfig, Config},
+    oauth, output,
+};
+
+/// File in which librespot saves the credentials inside the credential cache
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+/// The account that ffspot logs in as
+pub struct Account<'a> {
+    /// Name of the account in the config, `None` for the top-level credentials
+    pub name: Option<&'a str>,
+    pub credentials: &'a AccountConfig,
+}
+
+impl Account<'_> {
+    /// Every named account gets its own subdirectory in the credential cache.
+    pub fn credcache_path(&self) -> io::Result<PathBuf> {
+        let root = get_credcache_path()?;
+        let Some(name) = self.name else {
+            return Ok(root);
+        };
+        let path = root.join(name);
+        if !path.is_dir() {
+            fs::create_dir_all(&path)?;
+        }
+        Ok(path)
+    }
+
+    fn cache(&self) -> Result<Cache> {
+        Ok(Cache::new(Some(self.credcache_path()?), None, None, None)?)
+    }
+}
+
+/// Selects the account given with `--account`, or the default account from the config.
+/// Accounts that aren't in the config can only be used after logging in with `ffspot login`.
+pub fn select_account<'a>(
+    cfg: &'a Config,
+    name: Option<&'a str>,
+    allow_new: bool,
+) -> Result<Account<'a>> {
+    let Some(name) = name.or(cfg.default_account.as_deref()) else {
+        return Ok(Account {
+            name: None,
+            credentials: &cfg.account,
+        });
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Invalid account name {name:?}, only letters, numbers, - and _ are allowed");
+    }
+
+    static NOT_CONFIGURED: AccountConfig = AccountConfig {
+        username: None,
+        password: None,
+        password_command: None,
+        password_file: None,
+    };
+    let credentials = match cfg.accounts.get(name) {
+        Some(credentials) => credentials,
+        None if allow_new || get_credcache_path()?.join(name).join(CREDENTIALS_FILE).exists() => {
+            &NOT_CONFIGURED
+        }
+        None => bail!(
+            "Account {name:?} isn't defined in the config and has no saved credentials. Add an [accounts.{name}] section or run `ffspot --account {name} login`."
+        ),
+    };
+    Ok(Account {
+        name: Some(name),
+        credentials,
+    })
+}
+
+/// Logs in with the cached credentials, or with a username and password if there are none.
+pub async fn login(account: &Account<'_>) -> Result<(Session, String)> {
+    let cache = account.cache()?;
+    let credentials = match cache.credentials() {
+        Some(credentials) => credentials,
+        None => Credentials::with_password(
+            username(account.credentials, None)?,
+            password(account.credentials)?,
+        ),
+    };
+
+    let mut username = credentials.username.clone();
+    if credentials.auth_type == AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS {
+        username.push_str(" (cached credentials)");
+    }
+    if let Some(name) = account.name {
+        username.push_str(&format!(" [account {name}]"));
+    }
+    let session = Session::new(SessionConfig::default(), Some(cache));
+    Session::connect(&session, credentials, true).await?;
+
+    Ok((session, username))
+}
+
+/// Subscription details of the logged in account
+pub struct AccountInfo {
+    /// `premium`, `free`, `open`, ...
+    pub product: Option<String>,
+    /// The catalogue the account has access to, `premium` or `free`
+    pub catalogue: Option<String>,
+    pub country: String,
+}
+
+impl AccountInfo {
+    /// Reads the user attributes of the session. Spotify sends them separately after the login,
+    /// so this waits a few seconds for them to arrive.
+    pub async fn fetch(session: &Session) -> Self {
+        for _ in 0..50 {
+            if session.get_user_attribute("type").is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Self {
+            product: session.get_user_attribute("type"),
+            catalogue: session.get_user_attribute("catalogue"),
+            country: session.country(),
+        }
+    }
+
+    /// Audio can only be downloaded with Premium. Returns `None` if Spotify didn't say.
+    pub fn is_premium(&self) -> Option<bool> {
+        match (&self.product, &self.catalogue) {
+            (None, None) => None,
+            (product, catalogue) => Some(
+                product.as_deref() == Some("premium") || catalogue.as_deref() == Some("premium"),
+            ),
+        }
+    }
+
+    /// Fails with a clear message instead of letting every track fail with audio key errors.
+    pub fn ensure_premium(&self) -> Result<()> {
+        match self.is_premium() {
+            Some(true) => {}
+            Some(false) => bail!(
+                "This account has a {:?} subscription, but downloading requires Spotify Premium.",
+                self.product.as_deref().unwrap_or("free")
+            ),
+            None => output::status!(
+                "{}",
+                "Could not determine the subscription type of the account, downloads will fail if it isn't Premium.".bright_yellow()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Name of the temporary Spotify Connect device used by `login --zeroconf`
+const ZEROCONF_DEVICE_NAME: &str = "ffspot";
+
+/// Logs in with a username and password, ignoring the cached credentials, and saves
+/// the new credentials in the credential cache.
+pub async fn login_with_password(
+    account: &Account<'_>,
+    username_override: Option<String>,
+) -> Result<()> {
+    let credentials = Credentials::with_password(
+        username(account.credentials, username_override)?,
+        password(account.credentials)?,
+    );
+    save_login(account, credentials)
+        .await
+        .wrap_err("Login failed. Make sure that the username and password are correct.")
+}
+
+/// Advertises a Spotify Connect device on the local network and logs in with the credentials
+/// that the Spotify app sends to it once the device is selected.
+pub async fn login_zeroconf(account: &Account<'_>) -> Result<()> {
+    let session_config = SessionConfig::default();
+    let mut discovery = Discovery::builder(session_config.device_id, session_config.client_id)
+        .name(ZEROCONF_DEVICE_NAME)
+        .device_type(DeviceType::Computer)
+        .launch()
+        .wrap_err("Could not start the Spotify Connect device")?;
+
+    output::alert!(
+        "{} {ZEROCONF_DEVICE_NAME:?} {}",
+        "Select the device".bright_cyan(),
+        "in a Spotify app on the same network to log in...".bright_cyan()
+    );
+
+    let credentials = discovery
+        .next()
+        .await
+        .ok_or_eyre("The Spotify Connect device stopped before receiving any credentials")?;
+    drop(discovery);
+
+    save_login(account, credentials)
+        .await
+        .wrap_err("Login failed")
+}
+
+/// Logs in through the browser with OAuth.
+pub async fn login_oauth(account: &Account<'_>, cfg: &Config) -> Result<()> {
+    let credentials = oauth::authorize(&cfg.oauth).await?;
+    save_login(account, credentials)
+        .await
+        .wrap_err("Login failed")
+}
+
+/// Connects with the given credentials and saves them in the credential cache.
+async fn save_login(account: &Account<'_>, credentials: Credentials) -> Result<()> {
+    output::status!("{}", "Logging in...".bright_cyan());
+
+    let session = Session::new(SessionConfig::default(), Some(account.cache()?));
+    Session::connect(&session, credentials, true).await?;
+
+    output::status!(
+        "{}{}{}",
+        "Logged in as ".bright_green(),
+        session.username(),
+        ", the credentials have been saved.".bright_green()
+    );
+    Ok(())
+}
+
+/// Removes the saved credentials from the credential cache.
+pub fn logout(account: &Account<'_>) -> Result<()> {
+    let path = account.credcache_path()?.join(CREDENTIALS_FILE);
+    match fs::remove_file(&path) {
+        Ok(()) => output::status!(
+            "{}",
+            "The saved credentials have been removed.".bright_green()
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            output::status!("{}", "There are no saved credentials.".bright_cyan())
+        }
+        Err(e) => return Err(e).wrap_err_with(|| format!("Could not remove {path:?}")),
+    }
+    Ok(())
+}
+
+/// Finds the username, in order: the `login` argument, `FFSPOT_USERNAME`, the config
+/// and finally a prompt on the terminal.
+fn username(cfg: &AccountConfig, username_override: Option<String>) -> Result<String> {
+    if let Some(username) = username_override {
+        return Ok(username);
+    }
+    if let Ok(username) = env::var("FFSPOT_USERNAME") {
+        return Ok(username);
+    }
+    if let Some(username) = &cfg.username {
+        return Ok(username.clone());
+    }
+    if !io::stdin().is_terminal() {
+        bail!("No username configured. Set `username` in the config or FFSPOT_USERNAME, or run `ffspot login`.");
+    }
+    eprint!("Username: ");
+    io::stderr().flush()?;
+    let mut username = String::new();
+    io::stdin().read_line(&mut username)?;
+    Ok(username.trim().to_string())
+}
+
+/// Finds the password, in order: `FFSPOT_PASSWORD`, `password`, `password_command` and
+/// `password_file` from the config, and finally a hidden prompt on the terminal.
+fn password(cfg: &AccountConfig) -> Result<String> {
+    if let Ok(password) = env::var("FFSPOT_PASSWORD") {
+        return Ok(password);
+    }
+    if let Some(password) = &cfg.password {
+        return Ok(password.clone());
+    }
+    if let Some(command) = &cfg.password_command {
+        return password_from_command(command);
+    }
+    if let Some(path) = &cfg.password_file {
+        let password = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Could not read the password file {path:?}"))?;
+        return Ok(first_line(&password).to_string());
+    }
+    if !io::stdin().is_terminal() {
+        bail!("No password configured. Set one of `password`, `password_command` or `password_file` in the config or FFSPOT_PASSWORD, or run `ffspot login`.");
+    }
+    Ok(rpassword::prompt_password("Password: ")?)
+}
+
+fn password_from_command(command: &str) -> Result<String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .args([flag, command])
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .wrap_err_with(|| format!("Could not run the password command {command:?}"))?;
+    if !output.status.success() {
+        bail!(
+            "The password command {command:?} failed with {}",
+            output.status
+        );
+    }
+    Ok(first_line(&String::from_utf8(output.stdout)?).to_string())
+}
+
+/// Password managers like `pass` print the password on the first line, optionally
+/// followed by more data.
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or_default()
+}
+
+/// Lists the accounts that have saved credentials, with their usernames.
+pub fn cached_accounts() -> Result<Vec<(Option<String>, String)>> {
+    let root = get_credcache_path()?;
+    let read_username = |dir: &Path| -> Result<Option<String>> {
+        if !dir.join(CREDENTIALS_FILE).exists() {
+            return Ok(None);
+        }
+        let cache = Cache::new(Some(dir), None, None, None)?;
+        Ok(cache.credentials().map(|c| c.username))
+    };
+
+    let mut accounts = Vec::new();
+    if let Some(username) = read_username(&root)? {
+        accounts.push((None, username));
+    }
+    let mut named = Vec::new();
+    for entry in fs::read_dir(&root)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(username) = read_username(&entry.path())? {
+            named.push((
+                Some(entry.file_name().to_string_lossy().into_owned()),
+                username,
+            ));
+        }
+    }
+    named.sort();
+    accounts.extend(named);
+    Ok(accounts)
+}
+
+pub fn get_credcache_path() -> io::Result<PathBuf> {
+    if let Ok(path) = env::var("FFSPOT_CREDCACHE") {
+        return Ok(path.into());
+    }
+
+    let cachedir = if let Some(dir) = dirs::cache_dir() {
+        dir
+    } else {
+        env::current_dir()?
+    }
+    .join("ffspot")
+    .join("credentials_cache");
+
+    if !cachedir.is_dir() {
+        fs::create_dir_all(&cachedir)?;
+    }
+
+    Ok(cachedir)
+}
@@ -1,24 +1,243 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::output::Verbosity;
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  Everything was downloaded or skipped
+  1  Other errors
+  2  Invalid arguments
+  3  Some tracks failed or were left out
+  4  All tracks failed
+  5  Login failed, or the account can't download
+  6  The config file is missing or invalid";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = EXIT_CODES
+)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Named account from the config to use instead of the default one
+    #[arg(short, long, global = true)]
+    pub account: Option<String>,
+
+    /// When to use colors, `auto` disables them if NO_COLOR is set or stderr isn't a terminal
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_enum,
+        default_value_t = ColorChoice::Auto,
+        global = true
+    )]
+    pub color: ColorChoice,
+
+    /// Only print errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print more details, like the outcome of every track
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// Arguments of the default `download` command
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+impl Args {
+    pub fn verbosity(&self) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (_, true) => Verbosity::Verbose,
+            _ => Verbosity::Normal,
+        }
+    }
+
+    /// The command to run, `download` if no subcommand was given.
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Download(self.download))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Download a track, episode, album or playlist (the default command)
+    Download(DownloadArgs),
+
+    /// Print the metadata of all tracks in a resource, without downloading anything
+    Info {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = InfoFormat::Jsonl)]
+        format: InfoFormat,
+
+        /// Spotify URI/URL of the resource (track, episode, album, playlist)
+        resource: String,
+    },
+
+    /// Log in and save the credentials
+    Login {
+        /// Spotify username or e-mail, asked for if not given
+        username: Option<String>,
+
+        /// Log in by selecting a temporary Spotify Connect device in a Spotify app on the same
+        /// network, without entering a password. Also works for accounts that use social login.
+        #[arg(long, conflicts_with = "username")]
+        zeroconf: bool,
+
+        /// Log in through the browser with OAuth (see the [oauth] section of the config)
+        #[arg(long, conflicts_with_all = ["username", "zeroconf"])]
+        oauth: bool,
+    },
+
+    /// Remove the saved credentials
+    Logout,
+
+    /// Show which account ffspot logs in as, and which accounts have saved credentials
+    Whoami,
+
+    /// Manage the configuration file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Manage the credential cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Create the configuration file with the default settings
+    Init {
+        /// Replace an existing configuration file
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Check the configuration file for errors
+    Check,
+    /// Print the path of the configuration file
+    Path,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Print the path of the credential cache of the selected account
+    Path,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DownloadArgs {
     /// Use a different output path than in the config
     #[arg(short, long)]
     pub output: Option<String>,
 
-    /// Skip downloading existing files
-    #[arg(short, long)]
+    /// Skip downloading existing files (same as --on-existing skip)
+    #[arg(short, long, conflicts_with = "on_existing")]
     pub skip_existing: bool,
 
+    /// What to do with files that already exist [default: overwrite]
+    #[arg(long, value_enum)]
+    pub on_existing: Option<ExistingPolicy>,
+
+    /// With --on-existing upgrade, also replace files older than this many days
+    #[arg(long, value_name = "DAYS", requires = "on_existing")]
+    pub older_than: Option<u64>,
+
+    /// Only resolve the metadata and print what would be downloaded, and where
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Output format of --dry-run
+    #[arg(long, value_enum, default_value_t = PlanFormat::Table, requires = "dry_run")]
+    pub plan_format: PlanFormat,
+
+    /// Abort before downloading anything if some tracks are unavailable or were left out
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Write the availability of every track to a JSON file
+    #[arg(long, value_name = "PATH")]
+    pub preflight_report: Option<PathBuf>,
+
+    /// Write the outcome of every track to a JSON file after downloading
+    #[arg(long, value_name = "PATH")]
+    pub summary_json: Option<PathBuf>,
+
     /// Encoding profile from the config to use
     #[arg(short, long)]
     pub encoding_profile: Option<String>,
 
+    /// Print machine-readable progress events to stdout, one JSON object per line
+    #[arg(long, value_name = "FORMAT", value_enum, conflicts_with = "dry_run")]
+    pub events: Option<EventFormat>,
+
+    /// Print the output of ffmpeg while encoding, for debugging encoding profiles
+    #[arg(long)]
+    pub ffmpeg_verbose: bool,
+
     // Save the cover art of the first track in a directory as a file with the given name (relative to the track directory)
     #[arg(long)]
     pub external_cover_art: Option<String>,
 
-    /// Spotify URI/URL or the resource that you want to download (track, album, playlist, etc.)
-    pub resource: String,
+    /// Spotify URI/URL or the resource that you want to download (track, episode, album, playlist)
+    #[arg(required = true)]
+    pub resource: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum InfoFormat {
+    /// One JSON object per track
+    Jsonl,
+    /// A header row and one row per track, multiple values are separated by semicolons
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ExistingPolicy {
+    /// Keep the existing file
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Keep both files, the new one gets a number appended to its name
+    Rename,
+    /// Replace the file if it was downloaded in a lower quality (needs the spotify_id and
+    /// ffspot_source tags from the encoding profile), or if it's older than --older-than
+    Upgrade,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum EventFormat {
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum PlanFormat {
+    Table,
+    Json,
+}
+
+impl DownloadArgs {
+    pub fn existing_policy(&self) -> ExistingPolicy {
+        if self.skip_existing {
+            ExistingPolicy::Skip
+        } else {
+            self.on_existing.unwrap_or(ExistingPolicy::Overwrite)
+        }
+    }
 }
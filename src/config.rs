@@ -1,4 +1,7 @@
-use color_eyre::{eyre::eyre, Result};
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Result,
+};
 use serde::Deserialize;
 use std::{
     collections::HashMap,
@@ -8,37 +11,181 @@ use std::{
     path::PathBuf,
 };
 
+use crate::{filename::FilenameConfig, template::Template};
+
 #[derive(Deserialize)]
 pub struct Config {
-    pub username: String,
-    pub password: String,
+    /// Credentials of the account that is used when no named account is selected
+    #[serde(flatten)]
+    pub account: AccountConfig,
+    #[serde(default)]
+    pub default_account: Option<String>,
+    #[serde(default)]
+    pub accounts: HashMap<String, AccountConfig>,
     pub output: String,
     pub artists_separator: String,
+    #[serde(default = "default_featured_separator")]
+    pub featured_separator: String,
     pub default_profile: String,
+    #[serde(flatten)]
+    pub filename: FilenameConfig,
     #[serde(default)]
-    pub max_filename_len: Option<usize>,
+    pub on_collision: CollisionPolicy,
     #[serde(default = "default_ffpath")]
     pub ffpath: String,
+    #[serde(default)]
+    pub oauth: OAuthConfig,
     pub profiles: HashMap<String, EncodingProfile>,
 }
 
+/// Where the credentials of an account come from when there are no cached credentials
+#[derive(Deserialize)]
+pub struct AccountConfig {
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Shell command that prints the password, e.g. `pass show spotify`
+    #[serde(default)]
+    pub password_command: Option<String>,
+    /// File that contains the password on its first line
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+}
+
 #[derive(Deserialize)]
 pub struct EncodingProfile {
     pub quality: u16,
     pub cover_art: bool,
     pub extension: String,
     pub args: Vec<String>,
+    #[serde(default)]
+    pub multi_artist_tags: bool,
+}
+
+/// Settings for `ffspot login --oauth`
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct OAuthConfig {
+    pub client_id: String,
+    pub authorize_url: String,
+    pub token_url: String,
+    /// Port of the local listener that receives the redirect, 0 picks a free port
+    pub redirect_port: u16,
+    pub scopes: Vec<String>,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            // the client ID of the official desktop client, which is allowed to stream
+            client_id: "65b708073fc0480ea92a077233ca87bd".into(),
+            authorize_url: "https://accounts.spotify.com/authorize".into(),
+            token_url: "https://accounts.spotify.com/api/token".into(),
+            redirect_port: 8898,
+            scopes: vec!["streaming".into()],
+        }
+    }
+}
+
+/// What to do when multiple tracks in a download resolve to the same output path
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum CollisionPolicy {
+    /// Append " (2)", " (3)", ... to the file name
+    #[default]
+    Counter,
+    /// Append the track ID to the file name
+    TrackId,
+    /// Only download the first track
+    Skip,
+    /// Abort before downloading anything
+    Error,
 }
 
 fn default_ffpath() -> String {
     "ffmpeg".into()
 }
 
+fn default_featured_separator() -> String {
+    " feat. ".into()
+}
+
 pub enum LoadResult {
-    Opened(Config),
+    Opened(Box<Config>),
     Created(String),
 }
 
+/// Writes the default configuration file, refusing to replace an existing one unless `force` is set.
+pub fn init(force: bool) -> Result<PathBuf> {
+    let path = get_configpath()?;
+    if !force && path.exists() {
+        bail!("The configuration file {path:?} already exists. Use --force to replace it.");
+    }
+    fs::write(&path, include_bytes!("config.toml"))?;
+    Ok(path)
+}
+
+impl Config {
+    /// Checks the parts of the config that are only used later on, so that mistakes show up
+    /// before anything is downloaded.
+    pub fn check(&self) -> Result<()> {
+        self.check_general()?;
+        for (name, profile) in &self.profiles {
+            profile.check(name)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Config::check`], but only checks the encoding profile that is going to be used.
+    /// An unknown profile is left to the caller.
+    pub fn check_profile(&self, name: &str) -> Result<()> {
+        self.check_general()?;
+        if let Some(profile) = self.profiles.get(name) {
+            profile.check(name)?;
+        }
+        Ok(())
+    }
+
+    fn check_general(&self) -> Result<()> {
+        Template::compile(&self.output).wrap_err("Invalid `output` path template")?;
+        if let Some(account) = &self.default_account {
+            if !self.accounts.contains_key(account) {
+                bail!("The default account {account:?} isn't defined in [accounts]");
+            }
+        }
+        if !self.profiles.contains_key(&self.default_profile) {
+            bail!(
+                "The default profile {:?} isn't defined in [profiles]",
+                self.default_profile
+            );
+        }
+        Ok(())
+    }
+}
+
+impl EncodingProfile {
+    fn check(&self, name: &str) -> Result<()> {
+        if !matches!(self.quality, 320 | 160 | 96) {
+            bail!(
+                "Invalid quality '{}' in profile {name:?}, use 320, 160 or 96",
+                self.quality
+            );
+        }
+        // the tags are written by rewriting the Ogg comment header
+        if self.multi_artist_tags && !matches!(self.extension.as_str(), "ogg" | "oga" | "opus") {
+            bail!(
+                "`multi_artist_tags` in profile {name:?} only works with the ogg, oga and opus extensions"
+            );
+        }
+        for arg in &self.args {
+            Template::compile(arg)
+                .wrap_err_with(|| format!("Invalid argument in profile {name:?}"))?;
+        }
+        Ok(())
+    }
+}
+
 pub fn load() -> Result<LoadResult> {
     let path = get_configpath()?;
     match File::open(&path) {
@@ -56,7 +203,7 @@ pub fn load() -> Result<LoadResult> {
     }
 }
 
-fn get_configpath() -> io::Result<PathBuf> {
+pub fn get_configpath() -> io::Result<PathBuf> {
     if let Ok(path) = env::var("FFSPOT_CONFIG") {
         return Ok(path.into());
     }
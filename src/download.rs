@@ -4,23 +4,39 @@ use std::{
     ffi::OsString,
     fs,
     io::{self, ErrorKind, Read},
-    path::Path,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use async_tempfile::TempFile;
 use color_eyre::{
-    eyre::{bail, eyre, OptionExt},
+    eyre::{bail, eyre},
     Result,
 };
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressStyle};
 use librespot::{
     audio::AudioDecrypt,
-    core::{cdn_url::CdnUrl, session::Session, spotify_id::FileId},
-    metadata::{audio::AudioFileFormat, Track},
+    core::{
+        cdn_url::CdnUrl,
+        date::Date,
+        session::Session,
+        spotify_id::{FileId, SpotifyId},
+    },
+    metadata::{
+        album::AlbumType,
+        artist::{Artist, Artists},
+        audio::AudioFileFormat,
+        copyright::CopyrightType,
+        Album, Episode, Track,
+    },
 };
+use serde::Serialize;
 use tokio::{
     fs::{create_dir_all, OpenOptions},
     io::AsyncWriteExt,
@@ -29,32 +45,112 @@ use tokio::{
 use ureq::Response;
 
 use crate::{
-    cli::Args,
-    config::{Config, EncodingProfile},
-    resolve,
+    cli::{DownloadArgs, ExistingPolicy, PlanFormat},
+    config::{CollisionPolicy, Config, EncodingProfile},
+    error::{self, DownloadError},
+    events::{self, Event},
+    exit::ExitStatus,
+    ffmpeg,
+    filename::{self, SanitizePolicy},
+    ogg,
+    output::{self, Verbosity},
+    preflight::{self, Availability},
+    resolve::{self, Media, PlaylistInfo, ResolvedTrack},
     template::{self, Template},
 };
 
 fn select_file(
     files: &HashMap<AudioFileFormat, FileId>,
     allowed_formats: &[AudioFileFormat],
-) -> Option<FileId> {
+) -> Option<(AudioFileFormat, FileId)> {
     for allowed_format in allowed_formats {
         if let Some(file) = files.get(allowed_format) {
-            return Some(*file);
+            return Some((*allowed_format, *file));
         }
     }
     None
 }
 
+/// The Spotify formats that are downloaded for an encoding profile, best first
+#[derive(Clone, Copy)]
+pub struct AllowedFormats {
+    tracks: &'static [AudioFileFormat],
+    /// Episodes are often only available as MP3, so they fall back to it
+    episodes: &'static [AudioFileFormat],
+}
+
+impl AllowedFormats {
+    fn for_quality(quality: u16) -> Option<Self> {
+        Some(match quality {
+            320 => Self {
+                tracks: &[
+                    AudioFileFormat::OGG_VORBIS_320,
+                    AudioFileFormat::OGG_VORBIS_160,
+                    AudioFileFormat::OGG_VORBIS_96,
+                ],
+                episodes: &[
+                    AudioFileFormat::OGG_VORBIS_320,
+                    AudioFileFormat::OGG_VORBIS_160,
+                    AudioFileFormat::OGG_VORBIS_96,
+                    AudioFileFormat::MP3_320,
+                    AudioFileFormat::MP3_256,
+                    AudioFileFormat::MP3_160,
+                    AudioFileFormat::MP3_96,
+                ],
+            },
+            160 => Self {
+                tracks: &[
+                    AudioFileFormat::OGG_VORBIS_160,
+                    AudioFileFormat::OGG_VORBIS_96,
+                ],
+                episodes: &[
+                    AudioFileFormat::OGG_VORBIS_160,
+                    AudioFileFormat::OGG_VORBIS_96,
+                    AudioFileFormat::MP3_160,
+                    AudioFileFormat::MP3_96,
+                ],
+            },
+            96 => Self {
+                tracks: &[AudioFileFormat::OGG_VORBIS_96],
+                episodes: &[AudioFileFormat::OGG_VORBIS_96, AudioFileFormat::MP3_96],
+            },
+            _ => return None,
+        })
+    }
+
+    /// Picks the file of a track or episode to download.
+    pub fn select(self, media: &Media) -> Option<(AudioFileFormat, FileId)> {
+        let formats = match media {
+            Media::Track(..) => self.tracks,
+            Media::Episode(_) => self.episodes,
+        };
+        select_file(media.files(), formats)
+    }
+}
+
+/// Style of progress bars that count tracks, e.g. while resolving the metadata
+pub fn count_pb_style() -> ProgressStyle {
+    ProgressStyle::with_template("{spinner:.green} [{bar:40.blue}] {pos}/{len} {wide_msg:.green}")
+        .unwrap()
+        .progress_chars("-> ")
+}
+
+/// Bitrate of a Spotify audio format in kbps, from its name (e.g. `OGG_VORBIS_320`)
+fn source_kbps(format: &str) -> Option<u32> {
+    format.rsplit('_').next()?.parse().ok()
+}
+
 pub async fn download(
     resource_type: &str,
     resource_id: &str,
     session: Session,
     mut cfg: Config,
-    cli: &Args,
-) -> Result<()> {
+    cli: &DownloadArgs,
+) -> Result<ExitStatus> {
     let path_template = Template::compile(cli.output.as_deref().unwrap_or(&cfg.output))?;
+    if cli.older_than.is_some() && !matches!(cli.existing_policy(), ExistingPolicy::Upgrade) {
+        bail!("--older-than can only be used with --on-existing upgrade");
+    }
     let profile_name = cli
         .encoding_profile
         .as_deref()
@@ -63,192 +159,1093 @@ pub async fn download(
         bail!("Encoding profile {profile_name:?} not found");
     };
 
-    let allowed_formats: &[AudioFileFormat] = match profile.quality {
-        320 => &[
-            AudioFileFormat::OGG_VORBIS_320,
-            AudioFileFormat::OGG_VORBIS_160,
-            AudioFileFormat::OGG_VORBIS_96,
-        ],
-        160 => &[
-            AudioFileFormat::OGG_VORBIS_160,
-            AudioFileFormat::OGG_VORBIS_96,
-        ],
-        96 => &[AudioFileFormat::OGG_VORBIS_96],
-        e => return Err(eyre!("Invalid quality '{e}'")),
-    };
+    let allowed_formats = AllowedFormats::for_quality(profile.quality)
+        .ok_or_else(|| eyre!("Invalid quality '{}'", profile.quality))?;
 
     let mut profile_ffargs = Vec::with_capacity(profile.args.len());
     for arg in &profile.args {
         profile_ffargs.push(Template::compile(arg)?);
     }
 
-    let pbstyle_int = ProgressStyle::with_template(
-        "{spinner:.green} [{bar:40.blue}] {pos}/{len} {wide_msg:.green}",
+    let pbstyle_int = count_pb_style();
+
+    let pbstyle_data = ProgressStyle::with_template(
+        "{spinner:.green} [{bar:40.blue}] {bytes}/{total_bytes} {bytes_per_sec} {wide_msg:.green}",
     )
     .unwrap()
     .progress_chars("-> ");
 
-    let pbstyle_data = ProgressStyle::with_template(
-        "{spinner:.green} [{bar:40.blue}] {bytes}/{total_bytes} {bytes_per_sec} {wide_msg:.green}",
+    let pbstyle_encode = ProgressStyle::with_template(
+        "{spinner:.green} [{bar:40.cyan}] {percent:>3}% encoded {wide_msg:.green}",
     )
     .unwrap()
     .progress_chars("-> ");
 
-    let metadata_pb = ProgressBar::new(0);
+    let metadata_pb = output::progress_bar(0);
     metadata_pb.set_style(pbstyle_int.clone());
     metadata_pb.set_message("Resolving track metadata");
+    events::emit(Event::ResolveStarted {
+        resource_type,
+        resource_id,
+    });
+    if metadata_pb.is_hidden() {
+        output::status!("Resolving track metadata...");
+    }
 
-    let tracks = resolve::resolve_tracks(resource_type, resource_id, &session, metadata_pb).await?;
-
-    let track_count = tracks.len();
-    let seq_max_digits = track_count.to_string().len();
-
-    let ffpath = Arc::new(OsString::from(&cfg.ffpath));
-
-    let mut errors = Vec::new();
-    let mut skipped = 0;
-
-    for (seq, track) in tracks.into_iter().enumerate() {
-        let track_id = track.id;
-        let result = download_track(
-            track,
-            &path_template,
-            &session,
-            &cfg,
-            cli.skip_existing,
-            &profile,
-            seq + 1,
-            seq_max_digits,
-            allowed_formats,
-            pbstyle_data.clone(),
-            ffpath.clone(),
-            track_count,
-            &profile_ffargs,
-            cli.external_cover_art.as_deref(),
-        )
-        .await;
+    let resolved =
+        resolve::resolve_tracks(resource_type, resource_id, &session, metadata_pb).await?;
+
+    let track_count = resolved.tracks.len();
+    events::emit(Event::ResolveFinished {
+        tracks: track_count,
+        left_out: resolved.problems.len(),
+    });
+    output::detail!(
+        "Resolved {track_count} tracks, {} items were left out",
+        resolved.problems.len()
+    );
+
+    let ctx = DownloadContext {
+        path_template: &path_template,
+        session: &session,
+        cfg: &cfg,
+        on_existing: cli.existing_policy(),
+        older_than: cli
+            .older_than
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        profile: &profile,
+        seq_max_digits: track_count.to_string().len(),
+        allowed_formats,
+        pb_style: pbstyle_data,
+        encode_pb_style: pbstyle_encode,
+        ffpath: Arc::new(OsString::from(&cfg.ffpath)),
+        track_count,
+        profile_ffargs: &profile_ffargs,
+        external_cover_art: cli.external_cover_art.as_deref(),
+        playlist: resolved.playlist.as_ref(),
+        ffmpeg_verbose: cli.ffmpeg_verbose,
+    };
+
+    let availability = preflight::check(&resolved.tracks, allowed_formats, &session.country());
+    preflight::print_summary(&resolved.tracks, &availability);
+    if let Some(report_path) = &cli.preflight_report {
+        preflight::write_report(
+            report_path,
+            &session.country(),
+            &resolved.tracks,
+            &availability,
+            &resolved.problems,
+        )?;
+    }
+    let all_downloadable =
+        resolved.problems.is_empty() && availability.iter().all(|a| a.is_downloadable());
+    if cli.strict && !all_downloadable {
+        bail!("Some tracks can't be downloaded, aborting because of --strict");
+    }
 
-        match result {
-            Err(e) => errors.push((e, track_id)),
-            Ok(o) if !o => skipped += 1,
-            Ok(_) => {}
+    let plan = plan_downloads(resolved.tracks, &availability, &ctx).await?;
+
+    if cli.dry_run {
+        print_plan(&plan, &ctx, cli.plan_format)?;
+        return Ok(ExitStatus::Success);
+    }
+
+    let mut results = Vec::with_capacity(plan.len());
+    // which track each output path belongs to, the first one wins
+    let mut owners: HashMap<String, usize> = HashMap::new();
+    for (n, planned) in plan.iter().enumerate() {
+        if matches!(
+            planned.action,
+            Action::Download | Action::SkipExisting | Action::Link(_)
+        ) {
+            owners
+                .entry(collision_key(&planned.path, &cfg))
+                .or_insert(n);
         }
     }
 
-    let error_count = errors.len();
+    for (n, planned) in plan.iter().enumerate() {
+        let started = Instant::now();
+        let result = match planned.action {
+            Action::Download => download_track(planned, &ctx).await,
+            Action::Link(original) => {
+                let owner = owners.get(&collision_key(&planned.path, &cfg)).copied();
+                let taken_by = owner.filter(|&owner| owner != n);
+                link_track(&plan[original].path, &planned.path, taken_by).await
+            }
+            Action::SkipExisting
+            | Action::SkipCollision
+            | Action::SkipUnavailable
+            | Action::Duplicate => Ok(false),
+        };
 
-    for (error, id) in errors {
-        eprintln!(
-            "{} {id}\n{error:?}",
-            "An error has occurred while downloading track".bright_red()
-        );
+        let elapsed = started.elapsed();
+        emit_outcome(planned, &result);
+        print_outcome(planned, &result, elapsed, &ctx);
+        results.push((result, elapsed));
     }
 
-    eprintln!(
-        "{} ({skipped} {}, {} {})",
+    let counts = Counts::new(&plan, &results);
+    // unavailable tracks and left out items weren't downloaded either, even though they
+    // didn't cause an error
+    let failed = counts.failed + counts.unavailable + resolved.problems.len();
+    let status = ExitStatus::from_counts(counts.downloaded + counts.skipped, failed);
+    events::emit(Event::DownloadFinished {
+        downloaded: counts.downloaded,
+        skipped: counts.skipped,
+        failed,
+    });
+
+    if let Some(summary_path) = &cli.summary_json {
+        write_summary(
+            summary_path,
+            status,
+            &plan,
+            &results,
+            &availability,
+            &resolved.problems,
+        )?;
+    }
+
+    for (planned, (result, _)) in plan.iter().zip(results) {
+        if let Err(error) = result {
+            output::alert!(
+                "{} {}\n{:?}",
+                "An error has occurred while downloading track".bright_red(),
+                planned.resolved.media.id(),
+                color_eyre::Report::from(error)
+            );
+        }
+    }
+
+    print_relinks(&plan, &session);
+    preflight::print_problems(&resolved.problems);
+
+    output::status!(
+        "{} ({} {}, {} {}, {} {})",
         "Done!".bright_green(),
+        counts.skipped,
         "skipped".bright_cyan(),
-        error_count,
+        counts.unavailable,
+        "unavailable".bright_cyan(),
+        counts.failed,
         "errors".bright_cyan()
     );
 
+    Ok(status)
+}
+
+/// What happened to a track in the download
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Downloaded,
+    Skipped,
+    /// The preflight check found that the track can't be downloaded
+    Unavailable,
+    Failed,
+}
+
+impl Outcome {
+    fn of(planned: &PlannedTrack, result: &Result<bool, DownloadError>) -> Self {
+        match (result, &planned.action) {
+            (Err(_), _) => Self::Failed,
+            (Ok(_), Action::SkipUnavailable) => Self::Unavailable,
+            (Ok(true), _) => Self::Downloaded,
+            (Ok(false), _) => Self::Skipped,
+        }
+    }
+}
+
+/// Number of tracks with each outcome
+#[derive(Default)]
+struct Counts {
+    downloaded: usize,
+    skipped: usize,
+    unavailable: usize,
+    failed: usize,
+}
+
+impl Counts {
+    fn new(plan: &[PlannedTrack], results: &[(Result<bool, DownloadError>, Duration)]) -> Self {
+        let mut counts = Self::default();
+        for (planned, (result, _)) in plan.iter().zip(results) {
+            match Outcome::of(planned, result) {
+                Outcome::Downloaded => counts.downloaded += 1,
+                Outcome::Skipped => counts.skipped += 1,
+                Outcome::Unavailable => counts.unavailable += 1,
+                Outcome::Failed => counts.failed += 1,
+            }
+        }
+        counts
+    }
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    exit_code: u8,
+    downloaded: usize,
+    skipped: usize,
+    failed: usize,
+    tracks: Vec<SummaryEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct SummaryEntry<'a> {
+    position: usize,
+    /// `None` for items that were left out while resolving
+    track_id: Option<String>,
+    title: &'a str,
+    /// `downloaded`, `skipped` or `failed`
+    outcome: &'static str,
+    /// What was planned for the track, see `--dry-run`
+    action: Option<&'static str>,
+    /// The error followed by its causes
+    error: Vec<String>,
+    /// What kind of error it was, e.g. `cdn` or `ffmpeg`
+    error_kind: Option<&'static str>,
+    /// Whether trying again later might succeed
+    retryable: bool,
+    path: Option<&'a str>,
+    /// Time spent on the track, in seconds
+    duration: f64,
+}
+
+/// Writes the outcome of every track to a JSON file, for `--summary-json`.
+fn write_summary(
+    path: &Path,
+    status: ExitStatus,
+    plan: &[PlannedTrack],
+    results: &[(Result<bool, DownloadError>, Duration)],
+    availability: &[Availability],
+    problems: &[resolve::Problem],
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(plan.len() + problems.len());
+    for ((planned, (result, duration)), status) in plan.iter().zip(results).zip(availability) {
+        let media = &planned.resolved.media;
+        let (outcome, error) = match (Outcome::of(planned, result), result) {
+            (_, Err(e)) => ("failed", error::causes(e)),
+            (Outcome::Downloaded, _) => ("downloaded", Vec::new()),
+            (Outcome::Unavailable, _) => ("failed", vec![status.description().to_string()]),
+            _ => ("skipped", Vec::new()),
+        };
+        let error_kind = result.as_ref().err();
+        entries.push(SummaryEntry {
+            position: planned.resolved.position,
+            track_id: Some(media.id().to_base62()?),
+            title: media.name(),
+            outcome,
+            action: Some(planned.action.name()),
+            error,
+            error_kind: error_kind.map(DownloadError::kind),
+            retryable: error_kind.is_some_and(DownloadError::is_retryable),
+            path: Some(&planned.path),
+            duration: duration.as_secs_f64(),
+        });
+    }
+    for problem in problems {
+        entries.push(SummaryEntry {
+            position: problem.position,
+            track_id: None,
+            title: &problem.item,
+            outcome: "failed",
+            action: None,
+            error: vec![problem.reason.clone()],
+            error_kind: problem.kind,
+            retryable: problem.retryable,
+            path: None,
+            duration: 0.0,
+        });
+    }
+    entries.sort_by_key(|e| e.position);
+
+    let count = |outcome| entries.iter().filter(|e| e.outcome == outcome).count();
+    let summary = Summary {
+        exit_code: status.code(),
+        downloaded: count("downloaded"),
+        skipped: count("skipped"),
+        failed: count("failed"),
+        tracks: entries,
+    };
+    serde_json::to_writer_pretty(io::BufWriter::new(fs::File::create(path)?), &summary)?;
     Ok(())
 }
 
-async fn download_track(
-    track: Track,
-    path_template: &Template,
-    session: &Session,
-    cfg: &Config,
-    skip_existing: bool,
-    profile: &EncodingProfile,
-    seq: usize,
+fn emit_outcome(planned: &PlannedTrack, result: &Result<bool, DownloadError>) {
+    let seq = planned.seq;
+    match (result, &planned.action) {
+        (Err(e), _) => events::emit(Event::TrackFailed {
+            seq,
+            error_kind: e.kind(),
+            error: error::causes(e).join(": "),
+        }),
+        // downloads end with `encode-finished`
+        (Ok(_), Action::Download) => {}
+        (Ok(_), &Action::Link(original)) => events::emit(Event::TrackLinked {
+            seq,
+            path: &planned.path,
+            original: original + 1,
+        }),
+        (Ok(_), action) => events::emit(Event::TrackSkipped {
+            seq,
+            reason: action.name(),
+        }),
+    }
+}
+
+/// Prints a line per track when there are no progress bars to follow, e.g.
+/// "[12/300] Artist - Title: downloaded 8.10 MiB in 3.2s".
+fn print_outcome(
+    planned: &PlannedTrack,
+    result: &Result<bool, DownloadError>,
+    elapsed: Duration,
+    ctx: &DownloadContext,
+) {
+    if output::is_interactive() && !output::shows(Verbosity::Verbose) {
+        return;
+    }
+    let outcome = match (result, &planned.action) {
+        // the full error is printed at the end
+        (Err(e), _) => format!(
+            "failed: {}",
+            e.to_string().lines().next().unwrap_or_default()
+        ),
+        (Ok(_), Action::Download) => {
+            let size = fs::metadata(&planned.path).map_or(0, |m| m.len());
+            format!(
+                "downloaded {} in {:.1}s",
+                HumanBytes(size),
+                elapsed.as_secs_f64()
+            )
+        }
+        (Ok(_), Action::Link(original)) => format!("linked to track {}", original + 1),
+        (Ok(_), action) => format!("skipped ({})", action.name()),
+    };
+    let media = &planned.resolved.media;
+    output::status!(
+        "[{}/{}] {} - {}: {outcome}",
+        planned.seq,
+        ctx.track_count,
+        media.artist(),
+        media.name()
+    );
+}
+
+/// Lists the tracks that were replaced because they aren't available in the account's country.
+fn print_relinks(plan: &[PlannedTrack], session: &Session) {
+    let relinked: Vec<(&Track, &Track)> = plan
+        .iter()
+        .filter_map(|p| {
+            Some((
+                p.resolved.relinked_from.as_ref()?,
+                p.resolved.media.as_track()?.0,
+            ))
+        })
+        .collect();
+    if relinked.is_empty() {
+        return;
+    }
+    output::status!(
+        "{} {}{}",
+        relinked.len(),
+        "tracks are not available in ".bright_yellow(),
+        format!("{} and were replaced:", session.country()).bright_yellow()
+    );
+    for (original, track) in relinked {
+        output::status!(
+            "  {} ({}, {}) -> {} ({}, {})",
+            original.name,
+            original.album.name,
+            original.id,
+            track.name,
+            track.album.name,
+            track.id
+        );
+    }
+}
+
+/// State shared by all tracks in a single download
+struct DownloadContext<'a> {
+    path_template: &'a Template,
+    session: &'a Session,
+    cfg: &'a Config,
+    on_existing: ExistingPolicy,
+    older_than: Option<Duration>,
+    profile: &'a EncodingProfile,
     seq_max_digits: usize,
-    allowed_formats: &[AudioFileFormat],
+    allowed_formats: AllowedFormats,
     pb_style: ProgressStyle,
+    /// Style of the progress bar once the download is done and ffmpeg is still encoding
+    encode_pb_style: ProgressStyle,
     ffpath: Arc<OsString>,
     track_count: usize,
-    profile_ffargs: &[Template],
-    external_cover_art: Option<&str>,
-) -> Result<bool> {
-    let mut artists = String::new();
-    let last_n = track.artists.len() - 1;
-    for (n, artist) in track.artists.0.iter().enumerate() {
-        artists.push_str(&artist.name);
+    profile_ffargs: &'a [Template],
+    external_cover_art: Option<&'a str>,
+    playlist: Option<&'a PlaylistInfo>,
+    /// Print ffmpeg's output above the progress bars
+    ffmpeg_verbose: bool,
+}
+
+/// Stable name of an album type, as used in templates and `ffspot info`
+pub fn album_type_name(album_type: AlbumType) -> &'static str {
+    match album_type {
+        AlbumType::ALBUM => "album",
+        AlbumType::SINGLE => "single",
+        AlbumType::COMPILATION => "compilation",
+        AlbumType::EP => "ep",
+        AlbumType::AUDIOBOOK => "audiobook",
+        AlbumType::PODCAST => "podcast",
+    }
+}
+
+pub fn format_date(date: &Date) -> String {
+    format!(
+        "{:04}-{:02}-{:02}",
+        date.year(),
+        u8::from(date.month()),
+        date.day()
+    )
+}
+
+fn join_artists(artists: &Artists, separator: &str) -> String {
+    let mut joined = String::new();
+    let last_n = artists.len().saturating_sub(1);
+    for (n, artist) in artists.iter().enumerate() {
+        joined.push_str(&artist.name);
         if n != last_n {
-            artists.push_str(&cfg.artists_separator);
+            joined.push_str(separator);
         }
     }
+    joined
+}
+
+/// Formats the track artists as "Main feat. Featured". Artists that are also credited
+/// on the album count as main artists, and so does the first artist of the track.
+fn artists_feat(track: &Track, album: &Album, cfg: &Config) -> String {
+    let (main, featured): (Vec<_>, Vec<_>) = track
+        .artists
+        .iter()
+        .enumerate()
+        .partition(|(n, artist)| *n == 0 || album.artists.iter().any(|a| a.id == artist.id));
+    let join = |artists: Vec<(usize, &Artist)>| {
+        artists
+            .into_iter()
+            .map(|(_, a)| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(&cfg.artists_separator)
+    };
 
-    let template_fields = template::Fields {
-        artists: artists.into(),
-        title: track.name.into(),
-        album: track.album.name.into(),
+    let mut formatted = join(main);
+    if !featured.is_empty() {
+        formatted.push_str(&cfg.featured_separator);
+        formatted.push_str(&join(featured));
+    }
+    formatted
+}
+
+fn format_duration(duration_ms: i32) -> String {
+    let duration_secs = duration_ms / 1000;
+    format!("{}:{:02}", duration_secs / 60, duration_secs % 60)
+}
+
+fn template_fields<'a>(
+    resolved: &'a ResolvedTrack,
+    seq: usize,
+    source_format: Option<AudioFileFormat>,
+    ctx: &'a DownloadContext,
+) -> Result<template::Fields<'a>> {
+    let common = template::Fields {
         seq,
-        seq_digits: seq_max_digits,
+        seq_digits: ctx.seq_max_digits,
+        playlist_name: ctx.playlist.map_or("", |p| p.name.as_str()).into(),
+        playlist_owner: ctx.playlist.map_or("", |p| p.owner.as_str()).into(),
+        source_format: source_format
+            .map_or_else(String::new, |f| format!("{f:?}"))
+            .into(),
+        added_at: resolved
+            .added_at
+            .as_ref()
+            .map_or_else(String::new, format_date)
+            .into(),
+        ..Default::default()
+    };
+    match &resolved.media {
+        Media::Track(track, album) => track_fields(track, album, common, ctx),
+        Media::Episode(episode) => episode_fields(episode, common),
+    }
+}
+
+fn track_fields<'a>(
+    track: &'a Track,
+    album: &'a Album,
+    common: template::Fields<'a>,
+    ctx: &'a DownloadContext,
+) -> Result<template::Fields<'a>> {
+    let find_copyright = |copyright_type| {
+        album
+            .copyrights
+            .iter()
+            .find(|c| c.copyright_type == copyright_type)
+            .map_or("", |c| c.text.as_str())
+    };
+
+    let total_tracks = album
+        .discs
+        .iter()
+        .find(|d| d.number == track.disc_number)
+        .map_or(0, |d| d.tracks.len());
+
+    let artist_id = match track.artists.first() {
+        Some(artist) => artist.id.to_base62()?,
+        None => String::new(),
+    };
+
+    Ok(template::Fields {
+        artists: join_artists(&track.artists, &ctx.cfg.artists_separator).into(),
+        title: track.name.as_str().into(),
+        album: album.name.as_str().into(),
         track: track.number,
         disc: track.disc_number,
         language: track.language_of_performance.join(", ").into(),
-        year: track.album.date.year(),
-        publisher: track.album.label.into(),
+        year: album.date.year(),
+        publisher: album.label.as_str().into(),
+        artist: track.artists.first().map_or("", |a| a.name.as_str()).into(),
+        artists_feat: artists_feat(track, album, ctx.cfg).into(),
+        album_artist: album.artists.first().map_or("", |a| a.name.as_str()).into(),
+        album_artists: join_artists(&album.artists, &ctx.cfg.artists_separator).into(),
+        isrc: resolve::external_id(&track.external_ids, "isrc")
+            .unwrap_or_default()
+            .into(),
+        upc: resolve::external_id(&album.external_ids, "upc")
+            .unwrap_or_default()
+            .into(),
+        total_tracks,
+        total_discs: album.discs.len(),
+        release_date: format_date(&album.date).into(),
+        duration: format_duration(track.duration).into(),
+        duration_ms: track.duration,
+        explicit: track.is_explicit,
+        popularity: track.popularity,
+        album_type: album_type_name(album.album_type).into(),
+        copyright: find_copyright(CopyrightType::C).into(),
+        phonographic_copyright: find_copyright(CopyrightType::P).into(),
+        track_id: track.id.to_base62()?.into(),
+        track_uri: track.id.to_uri()?.into(),
+        album_id: album.id.to_base62()?.into(),
+        album_uri: album.id.to_uri()?.into(),
+        artist_id: artist_id.into(),
+        ..common
+    })
+}
+
+/// Podcast episodes use the show as their artist and album.
+fn episode_fields<'a>(
+    episode: &'a Episode,
+    common: template::Fields<'a>,
+) -> Result<template::Fields<'a>> {
+    let show = episode.show_name.as_str();
+    Ok(template::Fields {
+        artists: show.into(),
+        title: episode.name.as_str().into(),
+        album: show.into(),
+        track: episode.number,
+        disc: 1,
+        language: episode.language.as_str().into(),
+        year: episode.publish_time.year(),
+        artist: show.into(),
+        artists_feat: show.into(),
+        album_artist: show.into(),
+        album_artists: show.into(),
+        total_discs: 1,
+        release_date: format_date(&episode.publish_time).into(),
+        duration: format_duration(episode.duration).into(),
+        duration_ms: episode.duration,
+        explicit: episode.is_explicit,
+        album_type: "podcast".into(),
+        track_id: episode.id.to_base62()?.into(),
+        track_uri: episode.id.to_uri()?.into(),
+        ..common
+    })
+}
+
+/// What to do with a track in the download queue
+enum Action {
+    Download,
+    /// The file already exists and existing files should be skipped
+    SkipExisting,
+    /// Another track in the queue is saved to the same path
+    SkipCollision,
+    /// The same track is downloaded earlier in the queue, to a different path
+    Link(usize),
+    /// The same track is downloaded earlier in the queue, to the same path
+    Duplicate,
+    /// The preflight check found that the track can't be downloaded
+    SkipUnavailable,
+}
+
+struct PlannedTrack {
+    resolved: ResolvedTrack,
+    seq: usize,
+    path: String,
+    /// The audio file that will be downloaded, if there is one in a suitable format
+    file: Option<(AudioFileFormat, FileId)>,
+    /// Arguments from the encoding profile, with the wildcards resolved
+    ffargs: Vec<String>,
+    action: Action,
+    /// The path that the track would have been saved to if it didn't collide with another track
+    collided_with: Option<String>,
+}
+
+/// Key used for detecting output path collisions. Paths are compared case-insensitively
+/// when the files might end up on a case-insensitive filesystem.
+fn collision_key(path: &str, cfg: &Config) -> String {
+    match cfg.filename.sanitize.policy {
+        SanitizePolicy::Posix => path.to_string(),
+        SanitizePolicy::Windows | SanitizePolicy::Ascii => path.to_lowercase(),
+    }
+}
+
+/// Appends " (2)", " (3)", ... to the file name until `is_taken` returns false.
+fn numbered_path(
+    raw_path: &str,
+    id_suffix: &str,
+    cfg: &Config,
+    extension: &str,
+    is_taken: impl Fn(&str) -> bool,
+) -> Result<String> {
+    let mut counter = 2;
+    loop {
+        let disambiguation = format!(" ({counter})");
+        let path = filename::limit_path(
+            raw_path,
+            extension,
+            id_suffix,
+            &disambiguation,
+            &cfg.filename,
+        )?;
+        if !is_taken(&path) {
+            return Ok(path);
+        }
+        counter += 1;
+    }
+}
+
+/// Decides whether an existing file should be replaced with `--on-existing upgrade`.
+async fn needs_upgrade(
+    path: &str,
+    track_id: &str,
+    source_format: Option<AudioFileFormat>,
+    ctx: &DownloadContext<'_>,
+) -> Result<bool> {
+    let outdated = match ctx.older_than {
+        Some(max_age) => {
+            let modified = tokio::fs::metadata(path).await?.modified()?;
+            modified.elapsed().unwrap_or_default() > max_age
+        }
+        None => false,
+    };
+
+    let ffpath = ctx.ffpath.clone();
+    let file_path = PathBuf::from(path);
+    let Ok(tags) = task::spawn_blocking(move || ffmpeg::read_tags(&ffpath, &file_path)).await?
+    else {
+        // replace files that ffmpeg can't read, they are most likely broken
+        return Ok(true);
+    };
+    Ok(is_upgrade(&tags, track_id, source_format, outdated))
+}
+
+/// Whether an existing file with these tags should be replaced by the download of `track_id`
+/// from `source_format`. `outdated` files are replaced regardless of the format.
+fn is_upgrade(
+    tags: &HashMap<String, String>,
+    track_id: &str,
+    source_format: Option<AudioFileFormat>,
+    outdated: bool,
+) -> bool {
+    // a file of another track isn't ours to replace, the collision policy takes care of it
+    if tags
+        .get(ffmpeg::SPOTIFY_ID_TAG)
+        .is_some_and(|id| id != track_id)
+    {
+        return false;
+    }
+    if outdated {
+        return true;
+    }
+    let Some(source_format) = source_format else {
+        return false;
     };
+    let existing_kbps = tags
+        .get(ffmpeg::SOURCE_TAG)
+        .and_then(|format| source_kbps(format));
+    let new_kbps = source_kbps(&format!("{source_format:?}"));
+    existing_kbps.is_some_and(|existing| new_kbps.is_some_and(|new| new > existing))
+}
+
+/// Resolves the output paths of all tracks up front and decides what to do with each one.
+async fn plan_downloads(
+    tracks: Vec<ResolvedTrack>,
+    availability: &[Availability],
+    ctx: &DownloadContext<'_>,
+) -> Result<Vec<PlannedTrack>> {
+    let cfg = ctx.cfg;
+    let extension = &ctx.profile.extension;
+    let mut plan: Vec<PlannedTrack> = Vec::with_capacity(tracks.len());
+    // collision key -> index of the track in the plan
+    let mut paths: HashMap<String, usize> = HashMap::new();
+    let mut track_ids: HashMap<SpotifyId, usize> = HashMap::new();
+
+    for (n, resolved) in tracks.into_iter().enumerate() {
+        let seq = n + 1;
+        let file = ctx.allowed_formats.select(&resolved.media);
+        let source_format = file.map(|(format, _)| format);
+        let template_fields = template_fields(&resolved, seq, source_format, ctx)?;
+
+        let raw_path = ctx
+            .path_template
+            .resolve(&template_fields.sanitize_path(&cfg.filename.sanitize))?;
+        let track_id = &template_fields.track_id;
+        let id_suffix = format!(" [{}]", track_id.get(..8).unwrap_or(track_id));
+        let mut path = filename::limit_path(&raw_path, extension, &id_suffix, "", &cfg.filename)?;
+
+        let mut action = Action::Download;
+        let mut collided_with = None;
+        if !availability[n].is_downloadable() {
+            action = Action::SkipUnavailable;
+        } else if let Some(&original) = track_ids.get(&resolved.media.id()) {
+            action = if collision_key(&plan[original].path, cfg) == collision_key(&path, cfg) {
+                Action::Duplicate
+            } else {
+                Action::Link(original)
+            };
+        }
+
+        // links replace whatever is at their path, so they must not collide either
+        let colliding = paths.get(&collision_key(&path, cfg));
+        if let (Action::Download | Action::Link(_), Some(&other)) = (&action, colliding) {
+            let other: &PlannedTrack = &plan[other];
+            let colliding_path = path.clone();
+            match cfg.on_collision {
+                CollisionPolicy::Counter => {
+                    path = numbered_path(&raw_path, &id_suffix, cfg, extension, |p| {
+                        paths.contains_key(&collision_key(p, cfg))
+                    })?;
+                }
+                CollisionPolicy::TrackId => {
+                    path =
+                        filename::limit_path(&raw_path, extension, "", &id_suffix, &cfg.filename)?;
+                }
+                CollisionPolicy::Skip => action = Action::SkipCollision,
+                CollisionPolicy::Error => bail!(
+                    "Tracks {} and {track_id} would both be saved as {colliding_path:?}",
+                    other.resolved.media.id().to_base62()?
+                ),
+            }
+            if matches!(action, Action::SkipCollision) {
+                output::status!(
+                    "{} {colliding_path:?} (track {track_id})",
+                    "Skipping a track that would overwrite".bright_yellow()
+                );
+            } else {
+                output::status!(
+                    "{} {colliding_path:?}, saving track {track_id} as {path:?}",
+                    "Output path collision:".bright_yellow()
+                );
+            }
+            collided_with = Some(colliding_path);
+        }
+
+        if matches!(action, Action::Download | Action::Link(_)) && Path::new(&path).exists() {
+            match ctx.on_existing {
+                ExistingPolicy::Skip => action = Action::SkipExisting,
+                ExistingPolicy::Overwrite => {}
+                ExistingPolicy::Rename => {
+                    path = numbered_path(&raw_path, &id_suffix, cfg, extension, |p| {
+                        paths.contains_key(&collision_key(p, cfg)) || Path::new(p).exists()
+                    })?;
+                }
+                ExistingPolicy::Upgrade => {
+                    if !needs_upgrade(&path, track_id, source_format, ctx).await? {
+                        action = Action::SkipExisting;
+                    }
+                }
+            }
+        }
+
+        let mut ffargs = Vec::with_capacity(ctx.profile_ffargs.len());
+        for arg in ctx.profile_ffargs {
+            ffargs.push(arg.resolve(&template_fields)?);
+        }
+
+        match action {
+            Action::Download | Action::SkipExisting => {
+                track_ids.entry(resolved.media.id()).or_insert(n);
+                paths.insert(collision_key(&path, cfg), n);
+            }
+            Action::Link(_) => {
+                paths.insert(collision_key(&path, cfg), n);
+            }
+            Action::SkipCollision | Action::SkipUnavailable | Action::Duplicate => {}
+        }
+
+        plan.push(PlannedTrack {
+            resolved,
+            seq,
+            path,
+            file,
+            ffargs,
+            action,
+            collided_with,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// Arguments that come before the ones from the encoding profile. The audio is piped to stdin.
+/// Warnings are captured along with errors, so that they end up in the log. The encoding
+/// progress is written to stdout.
+const FFMPEG_INPUT_ARGS: &[&str] = &[
+    "-y",
+    "-hide_banner",
+    "-loglevel",
+    "warning",
+    "-nostats",
+    "-progress",
+    "pipe:1",
+    "-i",
+    "-",
+];
 
-    let mut path_string = path_template.resolve(&template_fields.sanitize_path())?;
-    if let Some(max_len) = cfg.max_filename_len {
-        path_string.truncate(max_len);
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Download => "download",
+            Self::SkipExisting => "skip-existing",
+            Self::SkipCollision => "skip-collision",
+            Self::Link(_) => "link",
+            Self::Duplicate => "duplicate",
+            Self::SkipUnavailable => "skip-unavailable",
+        }
     }
-    path_string.push('.');
-    path_string.push_str(&profile.extension);
-    let path = Path::new(&path_string);
+}
+
+#[derive(Serialize)]
+struct PlanEntry<'a> {
+    seq: usize,
+    track_id: String,
+    title: &'a str,
+    action: &'static str,
+    path: &'a str,
+    /// Path of the earlier track that a linked track is linked to
+    link_to: Option<&'a str>,
+    collided_with: Option<&'a str>,
+    /// ID of the original track, if it was replaced because it isn't available
+    relinked_from: Option<String>,
+    format: Option<String>,
+    ffmpeg_args: Vec<String>,
+}
+
+/// Prints what would be done with every track, for `--dry-run`.
+fn print_plan(plan: &[PlannedTrack], ctx: &DownloadContext, format: PlanFormat) -> Result<()> {
+    let mut entries = Vec::with_capacity(plan.len());
+    for planned in plan {
+        let media = &planned.resolved.media;
+        let ffmpeg_args = if matches!(planned.action, Action::Download) {
+            let mut args: Vec<String> = FFMPEG_INPUT_ARGS.iter().map(|&a| a.into()).collect();
+            if ctx.profile.cover_art && !media.covers().is_empty() {
+                args.extend(["-i".into(), "<cover art>".into()]);
+            }
+            args.extend(planned.ffargs.iter().cloned());
+            args.push(planned.path.clone());
+            args
+        } else {
+            Vec::new()
+        };
+        entries.push(PlanEntry {
+            seq: planned.seq,
+            track_id: media.id().to_base62()?,
+            title: media.name(),
+            action: planned.action.name(),
+            path: &planned.path,
+            link_to: match planned.action {
+                Action::Link(original) => Some(&plan[original].path),
+                _ => None,
+            },
+            collided_with: planned.collided_with.as_deref(),
+            relinked_from: match &planned.resolved.relinked_from {
+                Some(original) => Some(original.id.to_base62()?),
+                None => None,
+            },
+            format: planned.file.map(|(format, _)| format!("{format:?}")),
+            ffmpeg_args,
+        });
+    }
+
+    match format {
+        PlanFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout().lock(), &entries)?;
+            println!();
+        }
+        PlanFormat::Table => {
+            let seq_width = ctx.seq_max_digits;
+            for entry in &entries {
+                println!(
+                    "{:>seq_width$}  {:<14}  {:<14}  {}",
+                    entry.seq,
+                    entry.action,
+                    entry.format.as_deref().unwrap_or("no format"),
+                    entry.path
+                );
+                let indent = seq_width + 2;
+                if let Some(original) = entry.link_to {
+                    println!("{:indent$}linked to {original:?}", "");
+                }
+                if let Some(colliding_path) = entry.collided_with {
+                    println!("{:indent$}collided with {colliding_path:?}", "");
+                }
+                if let Some(original) = &entry.relinked_from {
+                    println!("{:indent$}replaces unavailable track {original}", "");
+                }
+                if !entry.ffmpeg_args.is_empty() {
+                    let args: Vec<Cow<str>> =
+                        entry.ffmpeg_args.iter().map(|a| shell_quote(a)).collect();
+                    let ffpath = ctx.ffpath.to_string_lossy();
+                    println!("{:indent$}{} {}", "", shell_quote(&ffpath), args.join(" "));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quotes an argument for display if it contains whitespace or quotes.
+fn shell_quote(arg: &str) -> Cow<'_, str> {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "'\"\\$`".contains(c)) {
+        format!("'{}'", arg.replace('\'', "'\\''")).into()
+    } else {
+        arg.into()
+    }
+}
+
+/// Links a track that appears multiple times in the queue to the already downloaded file,
+/// or copies it if the filesystem doesn't support hard links. `taken_by` is the position of
+/// another track that the plan gave the same path to, whose file must not be replaced.
+async fn link_track(
+    original: &str,
+    path: &str,
+    taken_by: Option<usize>,
+) -> Result<bool, DownloadError> {
+    if let Some(other) = taken_by {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!(
+                "Not linking over {path:?}, which belongs to track {}",
+                other + 1
+            ),
+        )
+        .into());
+    }
+    let path = Path::new(path);
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).await?;
+    }
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    if tokio::fs::hard_link(original, path).await.is_err() {
+        tokio::fs::copy(original, path).await?;
+    }
+    Ok(true)
+}
+
+async fn download_track(
+    planned: &PlannedTrack,
+    ctx: &DownloadContext<'_>,
+) -> Result<bool, DownloadError> {
+    let DownloadContext {
+        session,
+        profile,
+        track_count,
+        external_cover_art,
+        ..
+    } = *ctx;
+
+    let media = &planned.resolved.media;
+    let seq = planned.seq;
+    let path_string = &planned.path;
+    let path = Path::new(path_string);
 
     let parent = path
         .parent()
-        .ok_or_else(|| eyre!("Specified path has no parent"))?;
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Specified path has no parent"))?;
     create_dir_all(parent).await?;
 
-    if skip_existing && path.exists() {
-        return Ok(false);
-    }
-
     let filename = path.file_name().map_or_else(
         || path.to_string_lossy().to_string(),
         |v| v.to_string_lossy().to_string(),
     );
 
-    let display_id = track.id.to_base62()?;
+    let display_id = media.id().to_base62().map_err(DownloadError::Metadata)?;
 
-    let file = select_file(&track.files, allowed_formats)
-        .ok_or_else(|| eyre!("Could not find a suitable file for track {display_id:?}"))?;
+    let (format, file) = planned.file.ok_or(DownloadError::NoSuitableFormat)?;
+    // Spotify prepends a header of its own to Ogg files, which renders them corrupted
+    let header_len = match format {
+        AudioFileFormat::OGG_VORBIS_96
+        | AudioFileFormat::OGG_VORBIS_160
+        | AudioFileFormat::OGG_VORBIS_320 => 167,
+        _ => 0,
+    };
 
-    let key = session.audio_key().request(track.id, file).await?;
+    let key = session
+        .audio_key()
+        .request(media.id(), file)
+        .await
+        .map_err(DownloadError::audio_key)?;
 
-    let cdn_url = CdnUrl::new(file).resolve_audio(session).await?;
+    let cdn_url = CdnUrl::new(file)
+        .resolve_audio(session)
+        .await
+        .map_err(DownloadError::cdn)?;
 
-    let resp = task::spawn_blocking(move || -> Result<Response> {
-        Ok(ureq::get(cdn_url.try_get_url()?).call()?)
+    let resp = task::spawn_blocking(move || -> Result<Response, DownloadError> {
+        let url = cdn_url.try_get_url().map_err(DownloadError::cdn)?;
+        match ureq::get(url).call() {
+            Ok(resp) => Ok(resp),
+            Err(ureq::Error::Status(429, _)) => Err(DownloadError::RateLimited),
+            Err(e) => Err(DownloadError::cdn(e)),
+        }
     })
     .await??;
 
     let size = resp
         .header("content-length")
-        .ok_or_eyre("spotify cdn response didn't include content-length header")?
-        .parse()?;
+        .ok_or_else(|| {
+            DownloadError::cdn("spotify cdn response didn't include content-length header")
+        })?
+        .parse()
+        .map_err(DownloadError::cdn)?;
 
-    let download_pb = ProgressBar::new(size);
-    download_pb.set_style(pb_style);
+    let download_pb = output::progress_bar(size);
+    download_pb.set_style(ctx.pb_style.clone());
+    events::emit(Event::TrackStarted {
+        seq,
+        track_id: &display_id,
+        title: media.name(),
+        path: path_string,
+    });
 
-    let mut audio_stream = download_pb.wrap_read(AudioDecrypt::new(Some(key), resp.into_reader()));
+    let mut audio_stream = download_pb.wrap_read(events::ByteCounter::new(
+        AudioDecrypt::new(Some(key), resp.into_reader()),
+        seq,
+        size,
+    ));
 
-    let mut ffargs: Vec<Cow<'static, str>> = vec![
-        "-y".into(),
-        "-hide_banner".into(),
-        "-loglevel".into(),
-        "error".into(),
-        "-i".into(),
-        "-".into(),
-    ];
+    let mut ffargs: Vec<Cow<'static, str>> =
+        FFMPEG_INPUT_ARGS.iter().map(|&arg| arg.into()).collect();
 
-    let covers = track.album.covers.0;
+    let covers = media.covers();
     // keep the cover file in scope so that it only gets deleted after the download is finished
     let mut _cover: Option<TempFile>;
 
@@ -259,9 +1256,13 @@ async fn download_track(
             download_pb.set_message(format!(
                 "(downloading cover art...) [{seq}/{track_count}] {filename}"
             ));
-            let cover_data = spclient.get_image(&cover_id).await?;
+            events::emit(Event::CoverArt { seq });
+            let cover_data = spclient
+                .get_image(&cover_id)
+                .await
+                .map_err(DownloadError::cdn)?;
 
-            let mut cover_file = TempFile::new().await?;
+            let mut cover_file = TempFile::new().await.map_err(io::Error::other)?;
             cover_file.write_all(&cover_data).await?;
             ffargs.push("-i".into());
             ffargs.push(cover_file.file_path().to_string_lossy().into_owned().into());
@@ -278,58 +1279,211 @@ async fn download_track(
                     download_pb.set_message(format!(
                         "(downloading cover art...) [{seq}/{track_count}] {filename}"
                     ));
-                    let cover_data = spclient.get_image(&cover_id).await?;
+                    events::emit(Event::CoverArt { seq });
+                    let cover_data = spclient
+                        .get_image(&cover_id)
+                        .await
+                        .map_err(DownloadError::cdn)?;
 
                     cover_file.write_all(&cover_data).await?;
                 }
                 Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
-                Err(e) => bail!(e),
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
-    for arg in profile_ffargs {
-        ffargs.push(arg.resolve(&template_fields)?.into());
+    for arg in &planned.ffargs {
+        ffargs.push(arg.clone().into());
     }
 
-    ffargs.push(path_string.clone().into());
+    // encode next to the target, so that a failed download doesn't destroy an existing file
+    let partial = partial_path(path);
+    ffargs.push(partial.to_string_lossy().into_owned().into());
+
+    // episodes only have a single artist (the show), so there is nothing to split
+    let multi_valued_tags = match media.as_track() {
+        Some((track, album)) if profile.multi_artist_tags => {
+            let names = |artists: &Artists| -> Vec<String> {
+                artists.iter().map(|a| a.name.clone()).collect()
+            };
+            Some((
+                partial.clone(),
+                names(&track.artists),
+                names(&album.artists),
+            ))
+        }
+        _ => None,
+    };
 
     tracing::debug!("ffmpeg args built: {ffargs:?}");
 
-    let task = task::spawn_blocking(move || {
+    let ffpath = ctx.ffpath.clone();
+    let ffmpeg_verbose = ctx.ffmpeg_verbose;
+    let encode_pb_style = ctx.encode_pb_style.clone();
+    let duration_ms = u64::try_from(media.duration_ms()).unwrap_or_default();
+    let task = task::spawn_blocking(move || -> Result<(), DownloadError> {
         download_pb.set_message(format!("[{seq}/{track_count}] {filename}"));
 
         let mut ffmpeg = Command::new(&*ffpath)
             .args(ffargs.iter().map(AsRef::as_ref))
-            .stderr(Stdio::inherit())
-            .stdout(Stdio::inherit())
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
             .stdin(Stdio::piped())
             .spawn()?;
         let mut stdin = ffmpeg.stdin.take().unwrap();
+        let stderr = ffmpeg::capture_stderr(
+            ffmpeg.stderr.take().unwrap(),
+            display_id.clone(),
+            ffmpeg_verbose.then(|| download_pb.clone()),
+        );
 
-        // the first 167 bytes of the decrypted audio stream are useless
-        // and they render the ogg file corrupted, so we skip them
-        let mut garbage = [0u8; 167];
-        audio_stream.read_exact(&mut garbage)?;
+        // the bar shows the download until all audio has been passed to ffmpeg, and the
+        // encoding progress after that
+        let encoding = Arc::new(AtomicBool::new(false));
+        let encoded_ms = Arc::new(AtomicU64::new(0));
+        let progress = ffmpeg::watch_progress(ffmpeg.stdout.take().unwrap(), {
+            let pb = download_pb.clone();
+            let encoding = encoding.clone();
+            let encoded_ms = encoded_ms.clone();
+            move |out_time| {
+                let ms = u64::try_from(out_time.as_millis()).unwrap_or(u64::MAX);
+                encoded_ms.store(ms, Ordering::SeqCst);
+                if encoding.load(Ordering::SeqCst) {
+                    pb.set_position(ms);
+                }
+            }
+        });
 
-        io::copy(&mut audio_stream, &mut stdin)?;
+        let copied = io::copy(&mut (&mut audio_stream).take(header_len), &mut io::sink()).and_then(
+            |skipped| {
+                if skipped < header_len {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                io::copy(&mut audio_stream, &mut stdin)
+            },
+        );
 
         drop(stdin);
 
+        if copied.is_ok() {
+            events::emit(Event::EncodeStarted { seq });
+            if duration_ms > 0 {
+                download_pb.set_style(encode_pb_style);
+                download_pb.set_length(duration_ms);
+                // switch first, so that no progress update gets lost in between
+                encoding.store(true, Ordering::SeqCst);
+                download_pb.set_position(encoded_ms.load(Ordering::SeqCst));
+            }
+        }
+
         let status = ffmpeg.wait()?;
-        if status.success() {
-            Ok(())
-        } else if let Some(code) = status.code() {
-            Err(eyre!("ffmpeg exited with a non-zero exit code: {code}"))
-        } else {
-            Err(eyre!("ffmpeg was terminated by a signal"))
+        let stderr = stderr.join().expect("the ffmpeg stderr reader panicked");
+        progress
+            .join()
+            .expect("the ffmpeg progress reader panicked");
+        // if ffmpeg failed, writing to it most likely failed because of that
+        if !status.success() {
+            return Err(DownloadError::Ffmpeg {
+                code: status.code(),
+                stderr,
+            });
         }
+        copied?;
+        if let Some((path, artists, album_artists)) = multi_valued_tags {
+            // the file is fine without them, so this doesn't fail the track
+            if let Err(e) = ogg::write_multi_valued_tags(
+                &path,
+                &[("ARTIST", &artists), ("ALBUMARTIST", &album_artists)],
+            ) {
+                output::println_above(
+                    &download_pb,
+                    &format!(
+                        "{} {display_id}: {e}",
+                        "Could not write multi-valued artist tags for".bright_yellow()
+                    ),
+                );
+            }
+        }
+        Ok(())
     });
 
-    if let Err(e) = task.await? {
-        let _ = fs::remove_file(path);
+    let result = match task.await.unwrap_or_else(|e| Err(e.into())) {
+        Ok(()) => tokio::fs::rename(&partial, path).await.map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
         Err(e)
     } else {
+        events::emit(Event::EncodeFinished {
+            seq,
+            path: path_string,
+        });
         Ok(true)
     }
 }
+
+/// The file that a track is encoded into before it replaces `path`. The extension is kept,
+/// because ffmpeg picks the output format from it.
+fn partial_path(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("part.{}", extension.to_string_lossy())),
+        None => path.with_extension("part"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(id: &str, source: &str) -> HashMap<String, String> {
+        HashMap::from([
+            (ffmpeg::SPOTIFY_ID_TAG.to_string(), id.to_string()),
+            (ffmpeg::SOURCE_TAG.to_string(), source.to_string()),
+        ])
+    }
+
+    #[test]
+    fn upgrades_files_from_lower_bitrates() {
+        let existing = tags("a", "OGG_VORBIS_160");
+        let upgrade = |format, outdated| is_upgrade(&existing, "a", format, outdated);
+        assert!(upgrade(Some(AudioFileFormat::OGG_VORBIS_320), false));
+        assert!(!upgrade(Some(AudioFileFormat::OGG_VORBIS_160), false));
+        assert!(!upgrade(Some(AudioFileFormat::OGG_VORBIS_96), false));
+        assert!(upgrade(Some(AudioFileFormat::OGG_VORBIS_96), true));
+        // files without the tags are left alone
+        assert!(!is_upgrade(
+            &HashMap::new(),
+            "a",
+            Some(AudioFileFormat::OGG_VORBIS_320),
+            false
+        ));
+    }
+
+    #[test]
+    fn never_upgrades_files_of_other_tracks() {
+        let existing = tags("b", "OGG_VORBIS_96");
+        assert!(!is_upgrade(
+            &existing,
+            "a",
+            Some(AudioFileFormat::OGG_VORBIS_320),
+            false
+        ));
+        assert!(!is_upgrade(
+            &existing,
+            "a",
+            Some(AudioFileFormat::OGG_VORBIS_320),
+            true
+        ));
+    }
+
+    #[test]
+    fn keeps_the_extension_of_partial_files() {
+        assert_eq!(
+            partial_path(Path::new("dir/a.b.ogg")),
+            Path::new("dir/a.b.part.ogg")
+        );
+        assert_eq!(partial_path(Path::new("dir/a")), Path::new("dir/a.part"));
+    }
+}
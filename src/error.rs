@@ -0,0 +1,95 @@
+//! Errors of resolving and downloading single tracks. They are typed so that the caller can
+//! tell what went wrong, while color-eyre still renders them at the top level.
+
+use std::{error::Error, io};
+
+use librespot::core::error::ErrorKind;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("Could not get the decryption key of the audio file")]
+    AudioKey(#[source] librespot::core::Error),
+
+    #[error("Could not download from the Spotify CDN")]
+    Cdn(#[source] Box<dyn Error + Send + Sync>),
+
+    #[error("Could not find an audio file in a suitable format")]
+    NoSuitableFormat,
+
+    #[error("ffmpeg {}{}", exit_reason(*.code), stderr_suffix(.stderr))]
+    Ffmpeg {
+        /// `None` if ffmpeg was terminated by a signal
+        code: Option<i32>,
+        /// The last lines of ffmpeg's output
+        stderr: String,
+    },
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Spotify is rate limiting the requests")]
+    RateLimited,
+
+    #[error("Could not fetch the metadata")]
+    Metadata(#[source] librespot::core::Error),
+
+    #[error("The download task of the track failed")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+fn exit_reason(code: Option<i32>) -> String {
+    match code {
+        Some(code) => format!("exited with a non-zero exit code: {code}"),
+        None => "was terminated by a signal".into(),
+    }
+}
+
+fn stderr_suffix(stderr: &str) -> String {
+    let stderr = stderr.trim_end();
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!("\n{stderr}")
+    }
+}
+
+impl DownloadError {
+    /// Wraps a librespot error from requesting an audio key.
+    pub fn audio_key(e: librespot::core::Error) -> Self {
+        match e.kind {
+            ErrorKind::ResourceExhausted => Self::RateLimited,
+            _ => Self::AudioKey(e),
+        }
+    }
+
+    /// Wraps an error from downloading audio or images.
+    pub fn cdn(e: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::Cdn(e.into())
+    }
+
+    /// Short name of the variant, for machine-readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::AudioKey(_) => "audio-key",
+            Self::Cdn(_) => "cdn",
+            Self::NoSuitableFormat => "no-suitable-format",
+            Self::Ffmpeg { .. } => "ffmpeg",
+            Self::Io(_) => "io",
+            Self::RateLimited => "rate-limited",
+            Self::Metadata(_) => "metadata",
+            Self::Task(_) => "task",
+        }
+    }
+
+    /// Whether trying again later might succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Cdn(_) | Self::RateLimited | Self::AudioKey(_))
+    }
+}
+
+/// The error followed by its causes
+pub fn causes(error: &(dyn Error + 'static)) -> Vec<String> {
+    std::iter::successors(Some(error), |&e| e.source())
+        .map(ToString::to_string)
+        .collect()
+}
@@ -0,0 +1,131 @@
+//! Newline-delimited JSON events on stdout for `--events json`, so that other tools can
+//! follow a download without scraping the progress bars.
+
+use std::{
+    io::{self, Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Minimum time between two `bytes-downloaded` events of the same track
+const BYTES_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    ResolveStarted {
+        resource_type: &'a str,
+        resource_id: &'a str,
+    },
+    ResolveProgress {
+        resolved: usize,
+        total: usize,
+    },
+    ResolveFinished {
+        tracks: usize,
+        left_out: usize,
+    },
+    TrackStarted {
+        seq: usize,
+        track_id: &'a str,
+        title: &'a str,
+        path: &'a str,
+    },
+    CoverArt {
+        seq: usize,
+    },
+    BytesDownloaded {
+        seq: usize,
+        downloaded: u64,
+        total: u64,
+    },
+    EncodeStarted {
+        seq: usize,
+    },
+    EncodeFinished {
+        seq: usize,
+        path: &'a str,
+    },
+    TrackLinked {
+        seq: usize,
+        path: &'a str,
+        /// `seq` of the track that it is linked to
+        original: usize,
+    },
+    TrackSkipped {
+        seq: usize,
+        /// The planned action, see `--dry-run`
+        reason: &'static str,
+    },
+    TrackFailed {
+        seq: usize,
+        error_kind: &'static str,
+        error: String,
+    },
+    DownloadFinished {
+        downloaded: usize,
+        skipped: usize,
+        failed: usize,
+    },
+    RunFinished {
+        exit_code: u8,
+        error: Option<String>,
+    },
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Writes the event as a line of JSON to stdout, if events are enabled.
+pub fn emit(event: Event) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    // the reader might have gone away, which shouldn't stop the download
+    let _ = serde_json::to_writer(&mut stdout, &event);
+    let _ = writeln!(stdout);
+    let _ = stdout.flush();
+}
+
+/// Emits `bytes-downloaded` events while the audio is being read.
+pub struct ByteCounter<R> {
+    inner: R,
+    seq: usize,
+    downloaded: u64,
+    total: u64,
+    last_event: Instant,
+}
+
+impl<R> ByteCounter<R> {
+    pub fn new(inner: R, seq: usize, total: u64) -> Self {
+        Self {
+            inner,
+            seq,
+            downloaded: 0,
+            total,
+            last_event: Instant::now(),
+        }
+    }
+}
+
+impl<R: Read> Read for ByteCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.downloaded += read as u64;
+        if read == 0 || self.last_event.elapsed() >= BYTES_INTERVAL {
+            self.last_event = Instant::now();
+            emit(Event::BytesDownloaded {
+                seq: self.seq,
+                downloaded: self.downloaded,
+                total: self.total,
+            });
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,84 @@
+//! Exit codes, so that scripts can tell a partial failure from a total one.
+
+use std::process::ExitCode;
+
+use color_eyre::{eyre::eyre, Report};
+
+/// How the program ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Everything was downloaded or skipped
+    Success = 0,
+    /// Any other error, e.g. the resource couldn't be fetched
+    Error = 1,
+    /// Invalid arguments, the same code that clap exits with
+    Usage = 2,
+    /// Some tracks were downloaded, but others failed or were left out
+    PartialFailure = 3,
+    /// None of the tracks that should have been downloaded were
+    TotalFailure = 4,
+    /// Logging in failed, or the account can't download
+    AuthFailure = 5,
+    /// The config file is missing, invalid or refers to something that doesn't exist
+    ConfigError = 6,
+}
+
+impl ExitStatus {
+    /// Sums up a download from the number of tracks that were downloaded and that failed.
+    pub fn from_counts(succeeded: usize, failed: usize) -> Self {
+        match (succeeded, failed) {
+            (_, 0) => Self::Success,
+            (0, _) => Self::TotalFailure,
+            _ => Self::PartialFailure,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status.code())
+    }
+}
+
+/// An error that ends the program, with the exit status to end it with
+pub struct Failure {
+    pub report: Report,
+    pub status: ExitStatus,
+}
+
+impl Failure {
+    /// Ends the program because of invalid arguments.
+    pub fn usage(message: &str) -> Self {
+        Self {
+            report: eyre!("{message}"),
+            status: ExitStatus::Usage,
+        }
+    }
+}
+
+impl From<Report> for Failure {
+    fn from(report: Report) -> Self {
+        Self {
+            report,
+            status: ExitStatus::Error,
+        }
+    }
+}
+
+pub trait WithStatus<T> {
+    /// Ends the program with `status` if this is an error.
+    fn with_status(self, status: ExitStatus) -> Result<T, Failure>;
+}
+
+impl<T, E: Into<Report>> WithStatus<T> for Result<T, E> {
+    fn with_status(self, status: ExitStatus) -> Result<T, Failure> {
+        self.map_err(|e| Failure {
+            report: e.into(),
+            status,
+        })
+    }
+}
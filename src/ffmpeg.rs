@@ -0,0 +1,434 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsStr,
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{ChildStderr, ChildStdout, Command, Stdio},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use colored::Colorize;
+use indicatif::ProgressBar;
+
+use crate::{config::EncodingProfile, ogg, output};
+
+/// Tag with the Spotify ID of the track, written by the default encoding profiles
+pub const SPOTIFY_ID_TAG: &str = "spotify_id";
+
+/// Tag with the Spotify audio format that the file was encoded from
+pub const SOURCE_TAG: &str = "ffspot_source";
+
+/// Number of lines of ffmpeg's output that are attached to errors
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Reads ffmpeg's stderr on a separate thread and writes every line to the log, and with
+/// `live` also above the progress bar. Returns the last lines once ffmpeg exits.
+pub fn capture_stderr(
+    stderr: ChildStderr,
+    track: String,
+    live: Option<ProgressBar>,
+) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).split(b'\n') {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            tracing::info!(track, "ffmpeg: {line}");
+            if let Some(pb) = &live {
+                output::println_above(pb, &line);
+            }
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        Vec::from(tail).join("\n")
+    })
+}
+
+/// Reads the output of `-progress pipe:1` on a separate thread and calls `on_progress` with
+/// the position in the output file every time ffmpeg reports it.
+pub fn watch_progress(
+    stdout: ChildStdout,
+    on_progress: impl Fn(Duration) + Send + 'static,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            // "N/A" until the first packet has been written
+            if let Some(Ok(us)) = line.strip_prefix("out_time_us=").map(str::parse::<u64>) {
+                on_progress(Duration::from_micros(us));
+            }
+        }
+    })
+}
+
+/// Reads the global and stream tags of a media file. Keys are converted to lowercase.
+pub fn read_tags(ffpath: &OsStr, path: &Path) -> Result<HashMap<String, String>> {
+    let output = Command::new(ffpath)
+        .args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-f", "ffmetadata", "-"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        bail!("ffmpeg couldn't read the tags of {path:?}");
+    }
+    let mut tags = parse_ffmetadata(&String::from_utf8_lossy(&output.stdout));
+    // Vorbis comments are stream metadata, which the ffmetadata format leaves out
+    if let Ok(comments) = ogg::read_comments(path) {
+        for (key, value) in comments {
+            tags.entry(key).or_insert(value);
+        }
+    }
+    Ok(tags)
+}
+
+/// Parses FFmpeg's metadata format, ignoring the sections and comments.
+fn parse_ffmetadata(metadata: &str) -> HashMap<String, String> {
+    let mut tags = HashMap::new();
+    // set once the first unescaped `=` of the line has been read
+    let mut key: Option<String> = None;
+    let mut text = String::new();
+    let mut ignored = false;
+    let mut chars = metadata.chars();
+    loop {
+        let c = chars.next();
+        match c {
+            // escaped characters, including newlines in multi-line values
+            Some('\\') => {
+                if let Some(escaped) = chars.next() {
+                    text.push(escaped);
+                }
+                continue;
+            }
+            Some('\n') | None => {
+                if let (false, Some(key)) = (ignored, key.take()) {
+                    tags.entry(key.to_lowercase())
+                        .or_insert_with(|| text.clone());
+                }
+                text.clear();
+                ignored = false;
+            }
+            // sections and comments
+            Some(';' | '#' | '[') if text.is_empty() && key.is_none() => ignored = true,
+            Some('=') if key.is_none() => key = Some(std::mem::take(&mut text)),
+            Some(c) => text.push(c),
+        }
+        if c.is_none() {
+            break tags;
+        }
+    }
+}
+
+/// What the installed ffmpeg build supports
+pub struct Capabilities {
+    /// e.g. "ffmpeg version 6.1.1"
+    version: String,
+    /// Names of the encoders and of the codecs they encode
+    encoders: HashSet<String>,
+    muxers: HashSet<String>,
+}
+
+/// Muxers that ffmpeg picks for common output extensions
+const EXTENSION_MUXERS: &[(&str, &str)] = &[
+    ("aac", "adts"),
+    ("ac3", "ac3"),
+    ("aif", "aiff"),
+    ("aiff", "aiff"),
+    ("caf", "caf"),
+    ("flac", "flac"),
+    ("m4a", "ipod"),
+    ("m4b", "ipod"),
+    ("mka", "matroska"),
+    ("mkv", "matroska"),
+    ("mp2", "mp2"),
+    ("mp3", "mp3"),
+    ("mp4", "mp4"),
+    ("oga", "ogg"),
+    ("ogg", "ogg"),
+    ("opus", "opus"),
+    ("spx", "spx"),
+    ("wav", "wav"),
+    ("webm", "webm"),
+    ("wma", "asf"),
+    ("wv", "wv"),
+];
+
+impl Capabilities {
+    /// Asks ffmpeg for its version and the encoders and muxers it was built with.
+    pub fn probe(ffpath: &OsStr) -> Result<Self> {
+        let run = |arg: &str| -> Result<String> {
+            let output = Command::new(ffpath)
+                .args(["-hide_banner", arg])
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .wrap_err_with(|| format!("Could not run {ffpath:?}"))?;
+            if !output.status.success() {
+                bail!("`ffmpeg {arg}` failed with {}", output.status);
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        };
+
+        Ok(Self {
+            version: parse_version(&run("-version")?),
+            encoders: parse_encoders(&run("-encoders")?),
+            muxers: parse_muxers(&run("-muxers")?),
+        })
+    }
+
+    /// Checks that ffmpeg has the encoders that the profile's args ask for, and a muxer for
+    /// the output format.
+    pub fn check_profile(&self, name: &str, profile: &EncodingProfile) -> Result<()> {
+        let mut forced_format = false;
+        for pair in profile.args.windows(2) {
+            let (arg, value) = (&pair[0], &pair[1]);
+            // wildcards are only resolved per track, there is nothing to check in advance
+            if value.contains('{') || value.contains('%') {
+                continue;
+            }
+            if arg == "-f" {
+                if !self.muxers.contains(value) {
+                    bail!(
+                        "Profile {name:?} uses the output format {value:?}, but {} has no muxer for it. Check the spelling in `ffmpeg -muxers`, or install an FFmpeg build that supports it.",
+                        self.version
+                    );
+                }
+                forced_format = true;
+            } else if is_codec_option(arg) && value != "copy" && !self.encoders.contains(value) {
+                bail!(
+                    "Profile {name:?} uses the encoder {value:?}, but {} was built without it. Install an FFmpeg build with {value}, or change the profile's `args` to an encoder from `ffmpeg -encoders`.",
+                    self.version
+                );
+            }
+        }
+
+        if forced_format {
+            return Ok(());
+        }
+        let extension = profile.extension.to_lowercase();
+        match EXTENSION_MUXERS.iter().find(|(ext, _)| *ext == extension) {
+            Some((_, muxer)) if !self.muxers.contains(*muxer) => bail!(
+                "Profile {name:?} saves .{extension} files, but {} has no {muxer:?} muxer for them. Install an FFmpeg build that supports it, or change the profile's `extension`.",
+                self.version
+            ),
+            Some(_) => {}
+            None => output::status!(
+                "{}",
+                format!(
+                    "Could not check whether FFmpeg can write .{extension} files (profile {name:?}). If it can't, add \"-f\", \"<format>\" to the profile's args."
+                )
+                .bright_yellow()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// The first line of `ffmpeg -version` up to the version number, e.g. "ffmpeg version 6.1.1"
+fn parse_version(output: &str) -> String {
+    output
+        .split_whitespace()
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Names of the encoders in `ffmpeg -encoders`, and of the codecs they encode.
+fn parse_encoders(output: &str) -> HashSet<String> {
+    let mut encoders = HashSet::new();
+    for line in list_entries(output) {
+        let mut words = line.split_whitespace().skip(1);
+        if let Some(name) = words.next() {
+            encoders.insert(name.to_string());
+        }
+        // e.g. "libmp3lame  libmp3lame MP3 (MPEG audio layer 3) (codec mp3)"
+        if let Some(codec) = line
+            .strip_suffix(')')
+            .and_then(|l| l.rsplit_once("(codec "))
+            .map(|(_, codec)| codec)
+        {
+            encoders.insert(codec.to_string());
+        }
+    }
+    encoders
+}
+
+/// Names of the muxers in `ffmpeg -muxers`. Some entries have several comma-separated names.
+fn parse_muxers(output: &str) -> HashSet<String> {
+    let mut muxers = HashSet::new();
+    for line in list_entries(output) {
+        if let Some(names) = line.split_whitespace().nth(1) {
+            muxers.extend(names.split(',').map(str::to_string));
+        }
+    }
+    muxers
+}
+
+/// The lines of `ffmpeg -encoders` or `-muxers` after the legend, which ends with a line of dashes.
+fn list_entries(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .lines()
+        .skip_while(|line| {
+            let line = line.trim();
+            line.is_empty() || !line.chars().all(|c| c == '-')
+        })
+        .skip(1)
+}
+
+/// `-c`, `-codec`, `-acodec`, `-c:a`, `-codec:a:0` and so on
+fn is_codec_option(arg: &str) -> bool {
+    let option = arg.split(':').next().unwrap_or_default();
+    matches!(option, "-c" | "-codec" | "-acodec" | "-vcodec" | "-scodec")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODERS: &str = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ S..... = Subtitle
+ .F.... = Frame-level multithreading
+ ..S... = Slice-level multithreading
+ ...X.. = Codec is experimental
+ ....B. = Supports draw_horiz_band
+ .....D = Supports direct rendering method 1
+ ------
+ V....D mjpeg                Motion JPEG
+ A....D aac                  AAC (Advanced Audio Coding)
+ A....D flac                 FLAC (Free Lossless Audio Codec)
+ A....D libmp3lame           libmp3lame MP3 (MPEG audio layer 3) (codec mp3)
+ A....D libvorbis            libvorbis (codec vorbis)
+";
+
+    const MUXERS: &str = "\
+ File formats:
+ D. = Demuxing supported
+ .E = Muxing supported
+ --
+  E adts            ADTS AAC (Advanced Audio Coding)
+  E flac            raw FLAC
+  E ipod            iPod H.264 MP4 (MPEG-4 Part 14)
+  E matroska        Matroska
+  E mp3             MP3 (MPEG audio layer 3)
+  E ogg             Ogg
+  E oss,pulse       OSS and PulseAudio
+";
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            version: parse_version("ffmpeg version 6.1.1 Copyright (c) 2000-2023"),
+            encoders: parse_encoders(ENCODERS),
+            muxers: parse_muxers(MUXERS),
+        }
+    }
+
+    fn profile(extension: &str, args: &[&str]) -> EncodingProfile {
+        EncodingProfile {
+            quality: 320,
+            cover_art: false,
+            extension: extension.into(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            multi_artist_tags: false,
+        }
+    }
+
+    #[test]
+    fn parses_capabilities() {
+        let caps = capabilities();
+        assert_eq!(caps.version, "ffmpeg version 6.1.1");
+        let mut encoders: Vec<_> = caps.encoders.iter().map(String::as_str).collect();
+        encoders.sort();
+        assert_eq!(
+            encoders,
+            [
+                "aac",
+                "flac",
+                "libmp3lame",
+                "libvorbis",
+                "mjpeg",
+                "mp3",
+                "vorbis"
+            ]
+        );
+        let mut muxers: Vec<_> = caps.muxers.iter().map(String::as_str).collect();
+        muxers.sort();
+        assert_eq!(
+            muxers,
+            ["adts", "flac", "ipod", "matroska", "mp3", "ogg", "oss", "pulse"]
+        );
+    }
+
+    #[test]
+    fn accepts_supported_profiles() {
+        let caps = capabilities();
+        let check = |extension, args: &[&str]| caps.check_profile("p", &profile(extension, args));
+        assert!(check("mp3", &["-c:a", "libmp3lame"]).is_ok());
+        // the codec that an encoder is an alias for
+        assert!(check("mp3", &["-codec:a", "mp3"]).is_ok());
+        assert!(check("ogg", &["-c:a:0", "libvorbis", "-c:v", "copy"]).is_ok());
+        assert!(check("m4a", &["-acodec", "aac"]).is_ok());
+        assert!(check("bin", &["-f", "matroska", "-c:a", "flac"]).is_ok());
+        // wildcards are left alone
+        assert!(check("mp3", &["-metadata", "title={title}", "-c:a", "{codec}"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_unsupported_profiles() {
+        let caps = capabilities();
+        let check = |extension, args: &[&str]| caps.check_profile("p", &profile(extension, args));
+        assert!(check("opus", &["-c:a", "libopus"]).is_err());
+        assert!(check("ogg", &["-c:a:0", "libopus"]).is_err());
+        assert!(check("mp3", &["-f", "mp2"]).is_err());
+        // no opus muxer
+        assert!(check("opus", &["-c:a", "copy"]).is_err());
+    }
+
+    #[test]
+    fn parses_ffmetadata() {
+        let tags = parse_ffmetadata(
+            ";FFMETADATA1\n\
+             title=Song\n\
+             ARTIST=A\\; B\n\
+             # comment=no\n\
+             comment=first line\\\nsecond line\n\
+             equation=1\\=1\n\
+             key\\=with\\=equals=value=with=equals\n\
+             \\;not a comment=yes\n\
+             title=ignored duplicate\n\
+             [CHAPTER]\n\
+             TIMEBASE=1/1000\n\
+             no equals sign\n\
+             last=no newline",
+        );
+        let expected = [
+            ("title", "Song"),
+            ("artist", "A; B"),
+            ("comment", "first line\nsecond line"),
+            ("equation", "1=1"),
+            ("key=with=equals", "value=with=equals"),
+            (";not a comment", "yes"),
+            ("timebase", "1/1000"),
+            ("last", "no newline"),
+        ];
+        let expected: HashMap<String, String> = expected
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(tags, expected);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{borrow::Cow, collections::HashMap, path};
+
+use color_eyre::{eyre::bail, Result};
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Limits and sanitization rules for output paths
+#[derive(Deserialize, Default)]
+pub struct FilenameConfig {
+    #[serde(default)]
+    pub max_filename_len: Option<usize>,
+    #[serde(default)]
+    pub max_path_len: Option<usize>,
+    #[serde(default)]
+    pub filename_len_unit: LengthUnit,
+    #[serde(default)]
+    pub sanitize: SanitizeConfig,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LengthUnit {
+    #[default]
+    Bytes,
+    Chars,
+}
+
+impl LengthUnit {
+    fn len(self, s: &str) -> usize {
+        match self {
+            Self::Bytes => s.len(),
+            Self::Chars => s.chars().count(),
+        }
+    }
+
+    /// Cuts `s` down to at most `max` units without splitting a grapheme cluster.
+    fn truncate(self, s: &str, max: usize) -> &str {
+        let mut len = 0;
+        let mut end = 0;
+        for (pos, grapheme) in s.grapheme_indices(true) {
+            len += self.len(grapheme);
+            if len > max {
+                break;
+            }
+            end = pos + grapheme.len();
+        }
+        s[..end].trim_end()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SanitizePolicy {
+    /// Only replace `/` (and NUL)
+    Posix,
+    /// Replace characters that are invalid on Windows and avoid reserved names like `CON`
+    Windows,
+    /// Like `Windows`, but also transliterate all non-ASCII characters
+    Ascii,
+}
+
+impl Default for SanitizePolicy {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::Posix
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+}
+
+#[derive(Deserialize)]
+pub struct SanitizeConfig {
+    #[serde(default)]
+    pub policy: SanitizePolicy,
+    #[serde(default)]
+    pub normalization: Option<Normalization>,
+    /// Replacement for characters that are not allowed by the policy
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+    /// Custom replacements, applied before the policy
+    #[serde(default)]
+    pub replace: HashMap<char, String>,
+}
+
+impl Default for SanitizeConfig {
+    fn default() -> Self {
+        Self {
+            policy: SanitizePolicy::default(),
+            normalization: None,
+            replacement: default_replacement(),
+            replace: HashMap::new(),
+        }
+    }
+}
+
+fn default_replacement() -> String {
+    " ".into()
+}
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl SanitizeConfig {
+    fn is_forbidden(&self, c: char) -> bool {
+        match self.policy {
+            SanitizePolicy::Posix => c == '/' || c == '\0',
+            SanitizePolicy::Windows | SanitizePolicy::Ascii => {
+                matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control()
+            }
+        }
+    }
+
+    fn needs_change(&self, c: char) -> bool {
+        self.is_forbidden(c)
+            || self.replace.contains_key(&c)
+            || (self.policy == SanitizePolicy::Ascii && !c.is_ascii())
+    }
+
+    /// Makes a field value safe to use as a part of a file name.
+    pub fn sanitize<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        let normalized: Cow<str> = match self.normalization {
+            Some(Normalization::Nfc) => value.nfc().collect::<String>().into(),
+            Some(Normalization::Nfd) => value.nfd().collect::<String>().into(),
+            None if !value.contains(|c| self.needs_change(c)) => return value.into(),
+            None => value.into(),
+        };
+
+        let mut sanitized = String::with_capacity(normalized.len());
+        for c in normalized.chars() {
+            if let Some(replacement) = self.replace.get(&c) {
+                sanitized.push_str(replacement);
+            } else if self.is_forbidden(c) {
+                sanitized.push_str(&self.replacement);
+            } else if self.policy == SanitizePolicy::Ascii && !c.is_ascii() {
+                // transliterations can contain forbidden characters too, e.g. '½' -> "1/2"
+                match deunicode::deunicode_char(c) {
+                    Some(ascii) => {
+                        for c in ascii.chars() {
+                            if self.is_forbidden(c) {
+                                sanitized.push_str(&self.replacement);
+                            } else {
+                                sanitized.push(c);
+                            }
+                        }
+                    }
+                    None => sanitized.push_str(&self.replacement),
+                }
+            } else {
+                sanitized.push(c);
+            }
+        }
+        sanitized.into()
+    }
+
+    /// Stands in for a directory name that was cut down to nothing.
+    fn placeholder_component(&self) -> &str {
+        if self.replacement.trim_end_matches(['.', ' ']).is_empty() {
+            "_"
+        } else {
+            &self.replacement
+        }
+    }
+
+    /// Fixes up a whole path component on Windows, where names can't end with a dot
+    /// or a space, and where device names like `CON` are reserved even with an extension.
+    fn sanitize_component<'a>(&self, component: &'a str, is_file_name: bool) -> Cow<'a, str> {
+        if self.policy == SanitizePolicy::Posix || component == "." || component == ".." {
+            return component.into();
+        }
+        let mut component = Cow::Borrowed(component);
+        if !is_file_name {
+            let trimmed = component.trim_end_matches(['.', ' ']);
+            if trimmed.len() != component.len() {
+                component = trimmed.to_string().into();
+            }
+        }
+        let stem = component.split('.').next().unwrap_or_default();
+        if WINDOWS_RESERVED_NAMES
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(stem.trim_end()))
+        {
+            component = format!("_{component}").into();
+        }
+        component
+    }
+}
+
+/// Applies the filename length limits and path component rules of the sanitization policy
+/// from the config to a resolved output path and appends the extension.
+///
+/// Directory components are simply cut off, so that all tracks still end up in the same
+/// directory, while a shortened file name gets `suffix` appended to it so that the truncated
+/// names stay distinct. `disambiguation` is always appended to the file name and never cut off.
+pub fn limit_path(
+    path: &str,
+    extension: &str,
+    suffix: &str,
+    disambiguation: &str,
+    cfg: &FilenameConfig,
+) -> Result<String> {
+    let unit = cfg.filename_len_unit;
+    let extension = format!(".{extension}");
+
+    let mut output = String::with_capacity(path.len() + extension.len());
+    let mut name = "";
+    for component in path.split_inclusive(path::is_separator) {
+        let Some(dir) = component.strip_suffix(path::is_separator) else {
+            name = component;
+            break;
+        };
+        let limited = match cfg.max_filename_len {
+            Some(max) if unit.len(dir) > max => unit.truncate(dir, max),
+            _ => dir,
+        };
+        let mut limited = cfg.sanitize.sanitize_component(limited, false);
+        // an empty component would move the rest of the path to the parent, or to the root
+        if limited.is_empty() && !dir.is_empty() {
+            limited = cfg.sanitize.placeholder_component().into();
+        }
+        output.push_str(&limited);
+        output.push_str(&component[component.len() - 1..]);
+    }
+
+    let name = cfg.sanitize.sanitize_component(name, true);
+    let shorten = |max: usize| -> Result<String> {
+        let budget = max.saturating_sub(unit.len(disambiguation));
+        // drop the suffix if it doesn't leave any room for the name
+        let shortened = match budget.checked_sub(unit.len(suffix)) {
+            Some(budget) if budget > 0 => {
+                let name = unit.truncate(&name, budget);
+                format!("{name}{suffix}").trim_start().to_string()
+            }
+            _ => unit.truncate(&name, budget).to_string(),
+        };
+        if shortened.is_empty() {
+            bail!("The output path {path:?} can't be shortened enough to fit the length limits");
+        }
+        Ok(shortened + disambiguation)
+    };
+
+    let mut limited_name = match cfg.max_filename_len {
+        Some(max) if unit.len(&name) + unit.len(disambiguation) > max => shorten(max)?,
+        _ => format!("{name}{disambiguation}"),
+    };
+
+    if let Some(max) = cfg.max_path_len {
+        let fixed_len = unit.len(&output) + unit.len(&extension);
+        if fixed_len + unit.len(&limited_name) > max {
+            limited_name = shorten(max.saturating_sub(fixed_len))?;
+        }
+    }
+
+    output.push_str(&limited_name);
+    output.push_str(&extension);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(options: &str) -> FilenameConfig {
+        toml::from_str(options).unwrap()
+    }
+
+    #[test]
+    fn truncates_at_grapheme_boundaries() {
+        // "e" followed by a combining acute accent is a single grapheme of 3 bytes
+        let s = "ab\u{65}\u{301}cd";
+        assert_eq!(LengthUnit::Bytes.truncate(s, 4), "ab");
+        assert_eq!(LengthUnit::Bytes.truncate(s, 5), "ab\u{65}\u{301}");
+        assert_eq!(LengthUnit::Chars.truncate(s, 3), "ab");
+        assert_eq!(LengthUnit::Chars.truncate(s, 4), "ab\u{65}\u{301}");
+        // a grapheme that doesn't fit at all
+        assert_eq!(LengthUnit::Bytes.truncate("\u{1f600}", 3), "");
+    }
+
+    #[test]
+    fn keeps_truncated_directories_non_empty() {
+        let cfg = config("max_filename_len = 3\nsanitize = { replacement = \"-\" }");
+        let path = limit_path("\u{1f600}/x", "ogg", "", "", &cfg).unwrap();
+        assert_eq!(path, "-/x.ogg");
+
+        // the leading empty component of an absolute path stays empty
+        let path = limit_path("/\u{1f600}/x", "ogg", "", "", &cfg).unwrap();
+        assert_eq!(path, "/-/x.ogg");
+    }
+
+    #[test]
+    fn keeps_trimmed_directories_non_empty() {
+        let cfg = config("sanitize = { policy = \"windows\" }");
+        let path = limit_path("music/.../x", "ogg", "", "", &cfg).unwrap();
+        assert_eq!(path, "music/_/x.ogg");
+    }
+
+    #[test]
+    fn shortens_file_names_with_a_suffix() {
+        let cfg = config("max_filename_len = 10");
+        let path = limit_path("dir/abcdefghijkl", "ogg", " [1]", "", &cfg).unwrap();
+        assert_eq!(path, "dir/abcdef [1].ogg");
+    }
+}
@@ -0,0 +1,236 @@
+//! Metadata export for `ffspot info`.
+
+use std::io;
+
+use color_eyre::{eyre::bail, Result};
+use librespot::{core::session::Session, metadata::artist::Artists};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    cli::InfoFormat,
+    download, output, preflight,
+    resolve::{self, Media},
+};
+
+#[derive(Serialize, Default)]
+struct TrackInfo<'a> {
+    /// Position of the track in the album or playlist, starting at 1
+    position: usize,
+    /// `track` or `episode`
+    kind: &'static str,
+    track_id: String,
+    track_uri: String,
+    title: &'a str,
+    artists: Vec<&'a str>,
+    artist_ids: Vec<String>,
+    album: &'a str,
+    album_id: String,
+    album_artists: Vec<&'a str>,
+    album_type: &'static str,
+    disc_number: i32,
+    track_number: i32,
+    duration_ms: i32,
+    explicit: bool,
+    popularity: i32,
+    isrc: &'a str,
+    upc: &'a str,
+    release_date: String,
+    label: &'a str,
+    /// Audio formats that Spotify has for the track
+    formats: Vec<String>,
+    /// ID of the track in the resource, if it isn't available and was replaced by this one
+    relinked_from: Option<String>,
+    playlist_name: Option<&'a str>,
+    playlist_owner: Option<&'a str>,
+    added_at: Option<String>,
+}
+
+/// Separator of multiple values in a single CSV column
+const CSV_LIST_SEPARATOR: &str = ";";
+
+impl TrackInfo<'_> {
+    /// The names of the fields, in the same order as the values of [`TrackInfo::csv_record`]
+    fn csv_header() -> Result<Vec<String>> {
+        Ok(TrackInfo::default()
+            .csv_fields()?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    fn csv_record(&self) -> Result<Vec<String>> {
+        Ok(self
+            .csv_fields()?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    /// The serialized fields in the order of their declaration, as CSV values
+    fn csv_fields(&self) -> Result<Vec<(String, String)>> {
+        let Value::Object(fields) = serde_json::to_value(self)? else {
+            bail!("track info isn't serialized as a map");
+        };
+        Ok(fields
+            .into_iter()
+            .map(|(name, value)| (name, csv_value(value)))
+            .collect())
+    }
+}
+
+fn csv_value(value: Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s,
+        Value::Array(values) => values
+            .into_iter()
+            .map(csv_value)
+            .collect::<Vec<_>>()
+            .join(CSV_LIST_SEPARATOR),
+        value => value.to_string(),
+    }
+}
+
+fn names(artists: &Artists) -> Vec<&str> {
+    artists.iter().map(|a| a.name.as_str()).collect()
+}
+
+/// Resolves a resource and prints the metadata of all its tracks to stdout.
+pub async fn info(
+    resource_type: &str,
+    resource_id: &str,
+    session: Session,
+    format: InfoFormat,
+) -> Result<()> {
+    let metadata_pb = output::progress_bar(0);
+    metadata_pb.set_style(download::count_pb_style());
+    metadata_pb.set_message("Resolving track metadata");
+    if metadata_pb.is_hidden() {
+        output::status!("Resolving track metadata...");
+    }
+
+    let resolved =
+        resolve::resolve_tracks(resource_type, resource_id, &session, metadata_pb).await?;
+    let playlist = resolved.playlist.as_ref();
+
+    let mut csv_writer = match format {
+        InfoFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(io::stdout().lock());
+            writer.write_record(TrackInfo::csv_header()?)?;
+            Some(writer)
+        }
+        InfoFormat::Jsonl => None,
+    };
+
+    for resolved_track in &resolved.tracks {
+        let media = &resolved_track.media;
+        let mut formats: Vec<String> = media.files().keys().map(|f| format!("{f:?}")).collect();
+        formats.sort();
+        let relinked_from = match &resolved_track.relinked_from {
+            Some(original) => Some(original.id.to_base62()?),
+            None => None,
+        };
+
+        let info = match media {
+            Media::Track(track, album) => {
+                let mut artist_ids = Vec::with_capacity(track.artists.len());
+                for artist in track.artists.iter() {
+                    artist_ids.push(artist.id.to_base62()?);
+                }
+                TrackInfo {
+                    position: resolved_track.position,
+                    kind: "track",
+                    track_id: track.id.to_base62()?,
+                    track_uri: track.id.to_uri()?,
+                    title: &track.name,
+                    artists: names(&track.artists),
+                    artist_ids,
+                    album: &album.name,
+                    album_id: album.id.to_base62()?,
+                    album_artists: names(&album.artists),
+                    album_type: download::album_type_name(album.album_type),
+                    disc_number: track.disc_number,
+                    track_number: track.number,
+                    duration_ms: track.duration,
+                    explicit: track.is_explicit,
+                    popularity: track.popularity,
+                    isrc: resolve::external_id(&track.external_ids, "isrc").unwrap_or_default(),
+                    upc: resolve::external_id(&album.external_ids, "upc").unwrap_or_default(),
+                    release_date: download::format_date(&album.date),
+                    label: &album.label,
+                    formats,
+                    relinked_from,
+                    playlist_name: playlist.map(|p| p.name.as_str()),
+                    playlist_owner: playlist.map(|p| p.owner.as_str()),
+                    added_at: resolved_track.added_at.as_ref().map(download::format_date),
+                }
+            }
+            // the show takes the place of the artist and album
+            Media::Episode(episode) => TrackInfo {
+                position: resolved_track.position,
+                kind: "episode",
+                track_id: episode.id.to_base62()?,
+                track_uri: episode.id.to_uri()?,
+                title: &episode.name,
+                artists: vec![&episode.show_name],
+                artist_ids: Vec::new(),
+                album: &episode.show_name,
+                album_id: String::new(),
+                album_artists: vec![&episode.show_name],
+                album_type: "podcast",
+                disc_number: 1,
+                track_number: episode.number,
+                duration_ms: episode.duration,
+                explicit: episode.is_explicit,
+                popularity: 0,
+                isrc: "",
+                upc: "",
+                release_date: download::format_date(&episode.publish_time),
+                label: "",
+                formats,
+                relinked_from,
+                playlist_name: playlist.map(|p| p.name.as_str()),
+                playlist_owner: playlist.map(|p| p.owner.as_str()),
+                added_at: resolved_track.added_at.as_ref().map(download::format_date),
+            },
+        };
+
+        match &mut csv_writer {
+            Some(writer) => writer.write_record(info.csv_record()?)?,
+            None => println!("{}", serde_json::to_string(&info)?),
+        }
+    }
+
+    if let Some(mut writer) = csv_writer {
+        writer.flush()?;
+    }
+    preflight::print_problems(&resolved.problems);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_columns_follow_the_fields() {
+        let header = TrackInfo::csv_header().unwrap();
+        assert_eq!(header[..3], ["position", "kind", "track_id"]);
+        assert_eq!(header.last().map(String::as_str), Some("added_at"));
+
+        let info = TrackInfo {
+            position: 3,
+            artists: vec!["A", "B"],
+            explicit: true,
+            ..Default::default()
+        };
+        let record = info.csv_record().unwrap();
+        assert_eq!(record.len(), header.len());
+        let column = |name: &str| &record[header.iter().position(|h| h == name).unwrap()];
+        assert_eq!(column("position"), "3");
+        assert_eq!(column("artists"), "A;B");
+        assert_eq!(column("explicit"), "true");
+        assert_eq!(column("relinked_from"), "");
+    }
+}
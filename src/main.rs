@@ -1,34 +1,65 @@
 use std::{
-    env, fs, io,
+    env,
     path::{Path, PathBuf},
-    process,
+    process::ExitCode,
 };
 
 use clap::Parser;
+use cli::{CacheCommand, Command, ConfigCommand, DownloadArgs, EventFormat};
 use color_eyre::{
+    config::Theme,
     eyre::{bail, Context},
     Result,
 };
 use colored::Colorize;
-use config::LoadResult;
-use librespot::{
-    core::{cache::Cache, config::SessionConfig, session::Session},
-    discovery::Credentials, protocol::authentication::AuthenticationType,
-};
+use config::{EncodingProfile, LoadResult};
+use events::Event;
+use exit::{ExitStatus, Failure, WithStatus};
 use regex::Regex;
 use tracing::Level;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 
+mod auth;
 mod cli;
 mod config;
 mod download;
+mod error;
+mod events;
+mod exit;
+mod ffmpeg;
+mod filename;
+mod info;
+mod oauth;
+mod ogg;
+mod output;
+mod preflight;
 mod resolve;
 mod template;
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    let (status, error) = match run().await {
+        Ok(status) => (status, None),
+        Err(Failure { report, status }) => {
+            output::alert!("Error: {report:?}");
+            (status, Some(error::causes(report.as_ref()).join(": ")))
+        }
+    };
+    events::emit(Event::RunFinished {
+        exit_code: status.code(),
+        error,
+    });
+    status.into()
+}
+
+async fn run() -> Result<ExitStatus, Failure> {
+    let cli = cli::Args::parse();
+    let colors = output::init(cli.color, cli.verbosity());
+
+    let theme = if colors { Theme::dark() } else { Theme::new() };
     color_eyre::config::HookBuilder::default()
         .display_env_section(false)
+        .theme(theme)
         .install()?;
 
     #[cfg(windows)]
@@ -48,93 +79,190 @@ async fn main() -> Result<()> {
             .init();
     }
 
-    let cli = cli::Args::parse();
+    let account_name = cli.account.clone();
+    let command = cli.command();
+    if let Command::Download(DownloadArgs {
+        events: Some(EventFormat::Json),
+        ..
+    }) = &command
+    {
+        events::enable();
+    }
 
-    let config = match config::load()? {
-        LoadResult::Opened(c) => c,
+    match command {
+        Command::Config(ConfigCommand::Path) => {
+            let path = config::get_configpath().with_status(ExitStatus::ConfigError)?;
+            println!("{}", path.display());
+            return Ok(ExitStatus::Success);
+        }
+        Command::Config(ConfigCommand::Init { force }) => {
+            let path = config::init(force).with_status(ExitStatus::ConfigError)?;
+            output::status!(
+                "{} {}",
+                "A new configuration file has been created in".bright_green(),
+                path.display()
+            );
+            return Ok(ExitStatus::Success);
+        }
+        _ => {}
+    }
+
+    let config = match config::load().with_status(ExitStatus::ConfigError)? {
+        LoadResult::Opened(c) => *c,
         LoadResult::Created(path) => {
-            eprintln!(
+            output::status!(
                 "{} {path}\n{}",
                 "A new configuration file has been created in".bright_green(),
                 "Adjust it and run ffspot again.".bright_magenta()
             );
-            return Ok(());
+            return Ok(ExitStatus::ConfigError);
         }
     };
 
-    ffmpeg_healthcheck(&config.ffpath)?;
+    if let Command::Config(ConfigCommand::Check) = command {
+        config.check().with_status(ExitStatus::ConfigError)?;
+        let mut profiles: Vec<_> = config.profiles.iter().collect();
+        profiles.sort_by_key(|(name, _)| name.as_str());
+        ffmpeg_healthcheck(&config.ffpath, &profiles).with_status(ExitStatus::ConfigError)?;
+        output::status!("{}", "The configuration file is valid.".bright_green());
+        return Ok(ExitStatus::Success);
+    }
+
+    let account = auth::select_account(
+        &config,
+        account_name.as_deref(),
+        matches!(command, Command::Login { .. }),
+    )
+    .with_status(ExitStatus::ConfigError)?;
+
+    if let Command::Login {
+        username,
+        zeroconf,
+        oauth,
+    } = &command
+    {
+        let result = if *zeroconf {
+            auth::login_zeroconf(&account).await
+        } else if *oauth {
+            auth::login_oauth(&account, &config).await
+        } else {
+            auth::login_with_password(&account, username.clone()).await
+        };
+        result.with_status(ExitStatus::AuthFailure)?;
+        return Ok(ExitStatus::Success);
+    }
+
+    match command {
+        Command::Cache(CacheCommand::Path) => {
+            let path = account.credcache_path().with_status(ExitStatus::Error)?;
+            println!("{}", path.display());
+            return Ok(ExitStatus::Success);
+        }
+        Command::Logout => {
+            auth::logout(&account)?;
+            return Ok(ExitStatus::Success);
+        }
+        Command::Whoami => {
+            println!("Accounts with saved credentials:");
+            for (name, username) in auth::cached_accounts()? {
+                let selected = if name.as_deref() == account.name { "*" } else { " " };
+                let name = name.unwrap_or_else(|| "(no account name)".into());
+                println!("{selected} {name}: {username}");
+            }
+        }
+        _ => {}
+    }
 
-    let Some((resource_type, resource_id)) = parse_spotify_uri(&cli.resource) else {
-        eprintln!(
-            "{}",
-            "Error: The supplied resource URL/URI is invalid.".bright_red()
-        );
-        process::exit(2)
+    let resource = match &command {
+        Command::Info { resource, .. } => Some(resource.as_str()),
+        Command::Download(args) => {
+            let name = args
+                .encoding_profile
+                .as_ref()
+                .unwrap_or(&config.default_profile);
+            config
+                .check_profile(name)
+                .with_status(ExitStatus::ConfigError)?;
+            // an unknown --encoding-profile is reported by the download itself
+            let profiles: Vec<_> = config.profiles.get_key_value(name).into_iter().collect();
+            ffmpeg_healthcheck(&config.ffpath, &profiles).with_status(ExitStatus::ConfigError)?;
+            args.resource.as_deref()
+        }
+        _ => None,
+    };
+
+    let parsed_resource = match resource {
+        Some(resource) => match parse_spotify_uri(resource) {
+            Some(parsed) => Some(parsed),
+            None => return Err(Failure::usage("The supplied resource URL/URI is invalid.")),
+        },
+        None => None,
     };
 
-    eprintln!("{}", "Logging in...".bright_cyan());
+    output::status!("{}", "Logging in...".bright_cyan());
 
-    let (session, username) = login(&config.username, &config.password)
+    let (session, username) = auth::login(&account)
         .await
-        .wrap_err("Login failed. Make sure that the credentials in the config file are correct.")?;
+        .wrap_err("Login failed. Make sure that the credentials in the config file are correct.")
+        .with_status(ExitStatus::AuthFailure)?;
 
-    eprintln!("{}{}", "Logged in as ".bright_green(), username);
+    output::status!("{}{}", "Logged in as ".bright_green(), username);
 
-    download::download(resource_type, resource_id, session, config, &cli).await?;
+    let account_info = auth::AccountInfo::fetch(&session).await;
+    // a dry run doesn't download anything
+    if let Command::Download(DownloadArgs { dry_run: false, .. }) = &command {
+        account_info
+            .ensure_premium()
+            .with_status(ExitStatus::AuthFailure)?;
+    }
 
-    Ok(())
+    match (&command, parsed_resource) {
+        (Command::Info { format, .. }, Some((resource_type, resource_id))) => {
+            info::info(resource_type, resource_id, session, *format).await?
+        }
+        (Command::Download(args), Some((resource_type, resource_id))) => {
+            return Ok(download::download(resource_type, resource_id, session, config, args).await?)
+        }
+        (Command::Whoami, _) => {
+            let unknown = || "unknown".to_string();
+            println!("Username: {}", session.username());
+            println!(
+                "Product: {}",
+                account_info.product.clone().unwrap_or_else(unknown)
+            );
+            println!(
+                "Catalogue: {}",
+                account_info.catalogue.clone().unwrap_or_else(unknown)
+            );
+            println!("Country: {}", account_info.country);
+        }
+        _ => {}
+    }
+
+    Ok(ExitStatus::Success)
 }
 
-fn ffmpeg_healthcheck(ffpath: impl AsRef<Path>) -> Result<()> {
+/// Checks that FFmpeg is installed and supports the encoders and output formats of the profiles.
+fn ffmpeg_healthcheck(
+    ffpath: impl AsRef<Path>,
+    profiles: &[(&String, &EncodingProfile)],
+) -> Result<()> {
     let ffpath = ffpath.as_ref();
     if which::which(ffpath).is_err() {
         bail!("{ffpath:?} binary not found. Make sure FFmpeg is installed, or if you set a custom ffmpeg path, that the path is correct.");
     }
+    let capabilities = ffmpeg::Capabilities::probe(ffpath.as_os_str())?;
+    for (name, profile) in profiles {
+        capabilities.check_profile(name, profile)?;
+    }
     Ok(())
 }
 
 fn parse_spotify_uri(uri: &str) -> Option<(&str, &str)> {
     let regex = Regex::new(
-        r"(?:https?|spotify):(?://open\.spotify\.com/)?(track|album|playlist)[/:]([a-zA-Z\d]*)",
+        r"(?:https?|spotify):(?://open\.spotify\.com/)?(track|episode|album|playlist)[/:]([a-zA-Z\d]*)",
     )
     .unwrap();
     let captures = regex.captures(uri)?;
     Some((captures.get(1)?.as_str(), captures.get(2)?.as_str()))
 }
-
-async fn login(
-    username: impl Into<String>,
-    password: impl Into<String>,
-) -> Result<(Session, String)> {
-    let cache = Cache::new(Some(get_credcache_path()?), None, None, None)?;
-    let credentials = cache.credentials().unwrap_or_else(|| Credentials::with_password(username, password));
-
-    let mut username = credentials.username.clone();
-    if credentials.auth_type == AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS {
-        username.push_str(" (cached credentials)");
-    }
-    let session = Session::new(SessionConfig::default(), Some(cache));
-    Session::connect(&session, credentials, true).await?;
-
-    Ok((session, username))
-}
-
-fn get_credcache_path() -> io::Result<PathBuf> {
-    if let Ok(path) = env::var("FFSPOT_CREDCACHE") {
-        return Ok(path.into());
-    }
-
-    let cachedir = if let Some(dir) = dirs::cache_dir() {
-        dir
-    } else {
-        env::current_dir()?
-    }
-    .join("ffspot")
-    .join("credentials_cache");
-
-    if !cachedir.is_dir() {
-        fs::create_dir_all(&cachedir)?;
-    }
-
-    Ok(cachedir)
-}
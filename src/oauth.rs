@@ -0,0 +1,212 @@
+//! OAuth authorization code login (with PKCE) for `ffspot login --oauth`.
+//!
+//! The authorization URL is opened in the browser, and a short-lived HTTP listener on
+//! localhost receives the redirect with the authorization code.
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{TcpListener, TcpStream},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use colored::Colorize;
+use librespot::{discovery::Credentials, protocol::authentication::AuthenticationType};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::task;
+use url::Url;
+
+use crate::{config::OAuthConfig, output};
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// What the redirect listener received
+enum Redirect {
+    Code(String),
+    Denied(String),
+}
+
+/// Runs the authorization code flow and returns credentials for the access token.
+pub async fn authorize(cfg: &OAuthConfig) -> Result<Credentials> {
+    let listener = TcpListener::bind(("127.0.0.1", cfg.redirect_port)).wrap_err_with(|| {
+        format!(
+            "Could not listen on port {} for the OAuth redirect",
+            cfg.redirect_port
+        )
+    })?;
+    let redirect_uri = format!("http://127.0.0.1:{}/login", listener.local_addr()?.port());
+
+    let verifier: String = random_string(64);
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+    let state = random_string(16);
+
+    let mut auth_url = Url::parse(&cfg.authorize_url).wrap_err("Invalid `authorize_url`")?;
+    auth_url
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &cfg.client_id)
+        .append_pair("redirect_uri", &redirect_uri)
+        .append_pair("scope", &cfg.scopes.join(" "))
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("code_challenge", &challenge)
+        .append_pair("state", &state);
+
+    output::alert!(
+        "{}\n{auth_url}",
+        "Open this URL in a browser to log in:".bright_cyan()
+    );
+    open_browser(auth_url.as_str());
+
+    let expected_state = state.clone();
+    let redirect =
+        task::spawn_blocking(move || wait_for_redirect(&listener, &expected_state)).await??;
+    let code = match redirect {
+        Redirect::Code(code) => code,
+        Redirect::Denied(error) => bail!("The authorization was denied: {error}"),
+    };
+
+    let token_url = cfg.token_url.clone();
+    let client_id = cfg.client_id.clone();
+    let response = task::spawn_blocking(move || -> Result<TokenResponse> {
+        let body = ureq::post(&token_url)
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", &redirect_uri),
+                ("client_id", &client_id),
+                ("code_verifier", &verifier),
+            ])
+            .wrap_err("Could not exchange the authorization code for a token")?
+            .into_string()?;
+        Ok(serde_json::from_str(&body)?)
+    })
+    .await??;
+
+    Ok(Credentials {
+        username: String::new(),
+        auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+        auth_data: response.access_token.into_bytes(),
+    })
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Tries to open the URL in the default browser. The URL is printed anyway, so errors are ignored.
+fn open_browser(url: &str) {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+    let _ = command
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+}
+
+/// How long to wait for the browser to come back with the redirect
+const REDIRECT_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long a single connection may take to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts connections until one of them is the OAuth redirect. Anything else that connects
+/// to the port is ignored, and so are connections that fail.
+fn wait_for_redirect(listener: &TcpListener, state: &str) -> Result<Redirect> {
+    let deadline = Instant::now() + REDIRECT_TIMEOUT;
+    listener.set_nonblocking(true)?;
+    loop {
+        if Instant::now() >= deadline {
+            bail!(
+                "Timed out after {} minutes waiting for the OAuth redirect",
+                REDIRECT_TIMEOUT.as_secs() / 60
+            );
+        }
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(e) => return Err(e).wrap_err("The OAuth redirect listener failed"),
+        };
+        if stream.set_nonblocking(false).is_err()
+            || stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err()
+        {
+            continue;
+        }
+        let mut request_line = String::new();
+        if BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .is_err()
+        {
+            continue;
+        }
+
+        // e.g. "GET /login?code=...&state=... HTTP/1.1"
+        let Some(target) = request_line.split_whitespace().nth(1) else {
+            continue;
+        };
+        let Ok(url) = Url::parse(&format!("http://127.0.0.1{target}")) else {
+            continue;
+        };
+        if url.path() != "/login" {
+            respond(&mut stream, "404 Not Found", "Not found");
+            continue;
+        }
+
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+        };
+        if query("state").as_deref() != Some(state) {
+            respond(&mut stream, "400 Bad Request", "Invalid state, try again.");
+            continue;
+        }
+        if let Some(error) = query("error") {
+            respond(
+                &mut stream,
+                "200 OK",
+                "Login cancelled. You can close this tab.",
+            );
+            return Ok(Redirect::Denied(error));
+        }
+        if let Some(code) = query("code") {
+            respond(&mut stream, "200 OK", "Logged in. You can close this tab.");
+            return Ok(Redirect::Code(code));
+        }
+        respond(&mut stream, "400 Bad Request", "Missing authorization code");
+    }
+}
+
+/// Answers the browser. Errors are ignored, the tab might already be closed.
+fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+}
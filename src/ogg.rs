@@ -0,0 +1,467 @@
+//! Reading and rewriting of comment headers in Ogg Vorbis and Opus files.
+//!
+//! FFmpeg can only write a single value per tag, so multi-valued tags
+//! (one `ARTIST` comment per artist) are written by patching the file afterwards.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use color_eyre::{
+    eyre::{bail, OptionExt},
+    Result,
+};
+
+const PAGE_CONTINUED: u8 = 0x01;
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+struct Page {
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    seq: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn parse(buf: &[u8]) -> Result<(Self, usize)> {
+        if buf.len() < 27 || &buf[..4] != b"OggS" {
+            bail!("invalid Ogg page");
+        }
+        let segment_count = buf[26] as usize;
+        let segments = buf
+            .get(27..27 + segment_count)
+            .ok_or_eyre("truncated Ogg page")?;
+        let data_start = 27 + segment_count;
+        let data_end = data_start + segments.iter().map(|&s| s as usize).sum::<usize>();
+        let data = buf
+            .get(data_start..data_end)
+            .ok_or_eyre("truncated Ogg page")?;
+
+        let page = Self {
+            header_type: buf[5],
+            granule: u64::from_le_bytes(buf[6..14].try_into()?),
+            serial: u32::from_le_bytes(buf[14..18].try_into()?),
+            seq: u32::from_le_bytes(buf[18..22].try_into()?),
+            segments: segments.to_vec(),
+            data: data.to_vec(),
+        };
+        Ok((page, data_end))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(b"OggS\0");
+        out.push(self.header_type);
+        out.extend_from_slice(&self.granule.to_le_bytes());
+        out.extend_from_slice(&self.serial.to_le_bytes());
+        out.extend_from_slice(&self.seq.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.push(self.segments.len() as u8);
+        out.extend_from_slice(&self.segments);
+        out.extend_from_slice(&self.data);
+        let crc = crc32(&out[start..]);
+        out[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Codec {
+    Vorbis,
+    Opus,
+}
+
+impl Codec {
+    fn detect(id_header: &[u8]) -> Option<Self> {
+        if id_header.starts_with(b"\x01vorbis") {
+            Some(Self::Vorbis)
+        } else if id_header.starts_with(b"OpusHead") {
+            Some(Self::Opus)
+        } else {
+            None
+        }
+    }
+
+    fn header_count(self) -> usize {
+        match self {
+            Self::Vorbis => 3,
+            Self::Opus => 2,
+        }
+    }
+
+    fn comment_magic(self) -> &'static [u8] {
+        match self {
+            Self::Vorbis => b"\x03vorbis",
+            Self::Opus => b"OpusTags",
+        }
+    }
+}
+
+struct CommentHeader {
+    vendor: Vec<u8>,
+    comments: Vec<Vec<u8>>,
+    /// Data after the comment list (the framing bit in Vorbis, optional padding in Opus)
+    trailer: Vec<u8>,
+}
+
+impl CommentHeader {
+    fn parse(packet: &[u8]) -> Result<Self> {
+        fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+            if buf.len() < len {
+                bail!("truncated comment header");
+            }
+            let (taken, rest) = buf.split_at(len);
+            *buf = rest;
+            Ok(taken)
+        }
+        fn take_u32(buf: &mut &[u8]) -> Result<usize> {
+            Ok(u32::from_le_bytes(take(buf, 4)?.try_into()?) as usize)
+        }
+
+        let mut buf = packet;
+        let vendor_len = take_u32(&mut buf)?;
+        let vendor = take(&mut buf, vendor_len)?.to_vec();
+        let comment_count = take_u32(&mut buf)?;
+        let mut comments = Vec::with_capacity(comment_count);
+        for _ in 0..comment_count {
+            let len = take_u32(&mut buf)?;
+            comments.push(take(&mut buf, len)?.to_vec());
+        }
+        Ok(Self {
+            vendor,
+            comments,
+            trailer: buf.to_vec(),
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.vendor.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.vendor);
+        out.extend_from_slice(&(self.comments.len() as u32).to_le_bytes());
+        for comment in &self.comments {
+            out.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            out.extend_from_slice(comment);
+        }
+        out.extend_from_slice(&self.trailer);
+    }
+
+    /// Replaces all comments with the given key by one comment per value.
+    /// Does nothing if there is no comment with that key, so that only tags
+    /// that were requested in the encoding profile are written.
+    fn replace(&mut self, key: &str, values: &[String]) {
+        let matches_key = |comment: &[u8]| {
+            comment.len() > key.len()
+                && comment[key.len()] == b'='
+                && comment[..key.len()].eq_ignore_ascii_case(key.as_bytes())
+        };
+        let Some(pos) = self.comments.iter().position(|c| matches_key(c)) else {
+            return;
+        };
+        self.comments.retain(|c| !matches_key(c));
+        let new_comments = values.iter().map(|v| format!("{key}={v}").into_bytes());
+        self.comments.splice(pos..pos, new_comments);
+    }
+}
+
+/// Splits packets into Ogg pages, starting with sequence number `seq`.
+fn paginate(packets: &[Vec<u8>], serial: u32, mut seq: u32) -> Vec<Page> {
+    let mut laces = Vec::new();
+    for packet in packets {
+        laces.extend(std::iter::repeat_n(255, packet.len() / 255));
+        laces.push((packet.len() % 255) as u8);
+    }
+    let data: Vec<u8> = packets.concat();
+
+    let mut pages = Vec::new();
+    let mut data_pos = 0;
+    let mut continued = false;
+    for segments in laces.chunks(255) {
+        let data_len: usize = segments.iter().map(|&s| s as usize).sum();
+        // pages on which no packet ends have no granule position
+        let packet_ends = segments.iter().any(|&s| s < 255);
+        pages.push(Page {
+            header_type: if continued { PAGE_CONTINUED } else { 0 },
+            granule: if packet_ends { 0 } else { u64::MAX },
+            serial,
+            seq,
+            segments: segments.to_vec(),
+            data: data[data_pos..data_pos + data_len].to_vec(),
+        });
+        continued = segments.last() == Some(&255);
+        data_pos += data_len;
+        seq += 1;
+    }
+    pages
+}
+
+fn parse_pages(file: &[u8]) -> Result<Vec<Page>> {
+    let mut pages: Vec<Page> = Vec::new();
+    let mut pos = 0;
+    while pos < file.len() {
+        let (page, len) = Page::parse(&file[pos..])?;
+        // the pages of other streams would end up in the header packets
+        if pages
+            .first()
+            .is_some_and(|first| first.serial != page.serial)
+        {
+            bail!("Ogg files with more than one stream are not supported");
+        }
+        pages.push(page);
+        pos += len;
+    }
+    Ok(pages)
+}
+
+/// Reassembles the header packets, which always end at a page boundary.
+/// Returns the codec, the packets and the number of pages they take up.
+fn read_headers(pages: &[Page]) -> Result<(Codec, Vec<Vec<u8>>, usize)> {
+    let mut packets = vec![Vec::new()];
+    let mut codec = None;
+    let mut header_pages = 0;
+    for page in pages {
+        header_pages += 1;
+        let mut offset = 0;
+        for &lace in &page.segments {
+            let lace = lace as usize;
+            packets
+                .last_mut()
+                .unwrap()
+                .extend_from_slice(&page.data[offset..offset + lace]);
+            offset += lace;
+            if lace < 255 {
+                packets.push(Vec::new());
+            }
+        }
+        if codec.is_none() {
+            codec = Some(Codec::detect(&packets[0]).ok_or_eyre("not an Ogg Vorbis/Opus file")?);
+        }
+        if packets.len() > codec.unwrap().header_count() {
+            break;
+        }
+    }
+    let codec = codec.ok_or_eyre("empty Ogg file")?;
+    if packets.pop() != Some(Vec::new()) || packets.len() != codec.header_count() {
+        bail!("unexpected Ogg header layout");
+    }
+    Ok((codec, packets, header_pages))
+}
+
+/// Replaces every `(key, values)` tag in the comment header of an Ogg file in memory.
+fn rewrite_comments(file: &[u8], tags: &[(&str, &[String])]) -> Result<Vec<u8>> {
+    let mut pages = parse_pages(file)?;
+    let (codec, mut packets, header_pages) = read_headers(&pages)?;
+
+    let magic = codec.comment_magic();
+    let Some(comment_packet) = packets[1].strip_prefix(magic) else {
+        bail!("missing comment header");
+    };
+    let mut comments = CommentHeader::parse(comment_packet)?;
+    for (key, values) in tags {
+        comments.replace(key, values);
+    }
+    let mut comment_packet = magic.to_vec();
+    comments.write(&mut comment_packet);
+    packets[1] = comment_packet;
+
+    let first_page = &pages[0];
+    if first_page.segments.iter().position(|&s| s < 255) != Some(first_page.segments.len() - 1) {
+        bail!("unexpected Ogg header layout");
+    }
+    let new_header_pages = paginate(&packets[1..], first_page.serial, first_page.seq + 1);
+    let seq_shift = (1 + new_header_pages.len()) as i64 - header_pages as i64;
+
+    let mut out = Vec::with_capacity(file.len());
+    first_page.write(&mut out);
+    for page in &new_header_pages {
+        page.write(&mut out);
+    }
+    for page in &mut pages[header_pages..] {
+        page.seq = (page.seq as i64 + seq_shift) as u32;
+        page.write(&mut out);
+    }
+    Ok(out)
+}
+
+/// Reads the comments of an Ogg Vorbis or Opus file. Keys are converted to lowercase, and only
+/// the first value of each key is kept.
+pub fn read_comments(path: &Path) -> Result<HashMap<String, String>> {
+    parse_comments(&fs::read(path)?)
+}
+
+fn parse_comments(file: &[u8]) -> Result<HashMap<String, String>> {
+    let pages = parse_pages(file)?;
+    let (codec, packets, _) = read_headers(&pages)?;
+    let Some(comment_packet) = packets[1].strip_prefix(codec.comment_magic()) else {
+        bail!("missing comment header");
+    };
+    let mut tags = HashMap::new();
+    for comment in CommentHeader::parse(comment_packet)?.comments {
+        let comment = String::from_utf8_lossy(&comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.entry(key.to_lowercase())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    Ok(tags)
+}
+
+/// Rewrites the comment header of an Ogg Vorbis or Opus file, replacing every
+/// `(key, values)` tag with one comment per value.
+pub fn write_multi_valued_tags(path: &Path, tags: &[(&str, &[String])]) -> Result<()> {
+    let file = fs::read(path)?;
+    let out = rewrite_comments(&file, tags)?;
+    fs::write(path, out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vorbis_file(comments: &[&str], serial: u32) -> Vec<u8> {
+        let id_header = [b"\x01vorbis".as_slice(), &[0; 23]].concat();
+        let mut comment_header = b"\x03vorbis".to_vec();
+        CommentHeader {
+            vendor: b"test".to_vec(),
+            comments: comments.iter().map(|c| c.as_bytes().to_vec()).collect(),
+            trailer: vec![1],
+        }
+        .write(&mut comment_header);
+        let setup_header = [b"\x05vorbis".as_slice(), &[0; 10]].concat();
+
+        let mut out = Vec::new();
+        for page in paginate(&[id_header], serial, 0) {
+            page.write(&mut out);
+        }
+        for page in paginate(&[comment_header, setup_header], serial, 1) {
+            page.write(&mut out);
+        }
+        let mut audio = paginate(&[vec![7; 100]], serial, 2).remove(0);
+        audio.granule = 4096;
+        audio.write(&mut out);
+        out
+    }
+
+    fn comments(file: &[u8]) -> Vec<String> {
+        let pages = parse_pages(file).unwrap();
+        let (_, packets, _) = read_headers(&pages).unwrap();
+        let comments = CommentHeader::parse(&packets[1][7..]).unwrap();
+        comments
+            .comments
+            .into_iter()
+            .map(|c| String::from_utf8(c).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn crc_matches_the_ogg_polynomial() {
+        // CRC-32 with polynomial 0x04c11db7, no reflection, no initial value and no final XOR
+        assert_eq!(crc32(b"123456789"), 0x89a1_897f);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn written_pages_have_a_valid_crc() {
+        let file = vorbis_file(&["TITLE=x"], 1);
+        let mut pos = 0;
+        while pos < file.len() {
+            let (_, len) = Page::parse(&file[pos..]).unwrap();
+            let mut page = file[pos..pos + len].to_vec();
+            let stored = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(crc32(&page), stored);
+            pos += len;
+        }
+    }
+
+    #[test]
+    fn rewrites_the_comment_packet() {
+        let file = vorbis_file(&["TITLE=Song", "ARTIST=A; B", "ALBUM=Album"], 1);
+        let artists = ["A".to_string(), "B".to_string()];
+        let out =
+            rewrite_comments(&file, &[("ARTIST", &artists), ("ALBUMARTIST", &artists)]).unwrap();
+        // tags that weren't in the file are not added
+        assert_eq!(
+            comments(&out),
+            ["TITLE=Song", "ARTIST=A", "ARTIST=B", "ALBUM=Album"]
+        );
+        // the audio is left as it is
+        let pages = parse_pages(&out).unwrap();
+        let audio = pages.last().unwrap();
+        assert_eq!((audio.seq, audio.granule), (2, 4096));
+        assert_eq!(audio.data, [7; 100]);
+    }
+
+    #[test]
+    fn splits_large_comment_headers_across_pages() {
+        let file = vorbis_file(&["ARTIST=x"], 1);
+        let artists: Vec<String> = (0..300).map(|n| format!("{n:0>300}")).collect();
+        let out = rewrite_comments(&file, &[("ARTIST", &artists)]).unwrap();
+
+        let pages = parse_pages(&out).unwrap();
+        // the comment header doesn't fit on a single page of at most 255 * 255 bytes
+        assert_eq!(pages.len(), 4);
+        for (seq, page) in pages.iter().enumerate() {
+            assert_eq!(page.seq, seq as u32);
+            assert!(page.segments.len() <= 255);
+        }
+        assert_eq!(pages[2].header_type, PAGE_CONTINUED);
+        let expected: Vec<String> = artists.iter().map(|a| format!("ARTIST={a}")).collect();
+        assert_eq!(comments(&out), expected);
+
+        // and the result can be rewritten again
+        let out = rewrite_comments(&out, &[("ARTIST", &["y".to_string()])]).unwrap();
+        assert_eq!(comments(&out), ["ARTIST=y"]);
+        assert_eq!(parse_pages(&out).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reads_the_comments_ffmpeg_writes() {
+        // the comment header as ffmpeg's Ogg muxer writes it for the default `ogg` profile
+        let file = vorbis_file(
+            &[
+                "encoder=Lavc60.31.102 libvorbis",
+                "title=Song",
+                "spotify_id=4uLU6hMCjMI75M1A2tKUQC",
+                "FFSPOT_SOURCE=OGG_VORBIS_160",
+                "title=second value",
+            ],
+            1,
+        );
+        let tags = parse_comments(&file).unwrap();
+        assert_eq!(tags["title"], "Song");
+        assert_eq!(tags["spotify_id"], "4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(tags["ffspot_source"], "OGG_VORBIS_160");
+        assert_eq!(tags.len(), 4);
+    }
+
+    #[test]
+    fn rejects_multiple_streams() {
+        let file = [vorbis_file(&[], 1), vorbis_file(&[], 2)].concat();
+        assert!(rewrite_comments(&file, &[]).is_err());
+    }
+}
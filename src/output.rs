@@ -0,0 +1,95 @@
+//! Status messages and progress output on stderr. Progress bars are only drawn on a
+//! terminal, otherwise every track gets a line of its own, which reads well in logs.
+
+use std::{
+    env,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use indicatif::ProgressBar;
+
+use crate::cli::ColorChoice;
+
+/// How much is printed, from `--quiet` and `--verbose`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static INTERACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Sets up colors and the verbosity. Returns whether colors are enabled.
+pub fn init(color: ColorChoice, verbosity: Verbosity) -> bool {
+    let interactive = io::stderr().is_terminal();
+    // https://no-color.org
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let colors = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => interactive && !no_color,
+    };
+    colored::control::set_override(colors);
+    console::set_colors_enabled_stderr(colors);
+    INTERACTIVE.store(interactive, Ordering::Relaxed);
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+    colors
+}
+
+/// Whether messages of the given level are printed
+pub fn shows(level: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Whether stderr is a terminal that progress bars can be drawn on
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::Relaxed)
+}
+
+/// A progress bar that is only drawn on a terminal, and not with `--quiet`.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    if is_interactive() && shows(Verbosity::Normal) {
+        ProgressBar::new(len)
+    } else {
+        ProgressBar::hidden()
+    }
+}
+
+/// Prints a line above the progress bar, or on its own if the bar isn't drawn.
+pub fn println_above(pb: &ProgressBar, line: &str) {
+    if pb.is_hidden() {
+        eprintln!("{line}");
+    } else {
+        pb.println(line);
+    }
+}
+
+/// Prints a status message, unless `--quiet` is given.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::output::shows($crate::output::Verbosity::Normal) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a message that is only interesting with `--verbose`.
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if $crate::output::shows($crate::output::Verbosity::Verbose) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints an error, or something the user has to act on, even with `--quiet`.
+macro_rules! alert {
+    ($($arg:tt)*) => {
+        eprintln!($($arg)*)
+    };
+}
+
+pub(crate) use {alert, detail, status};
@@ -0,0 +1,177 @@
+//! Availability check of the resolved tracks, before anything is downloaded.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use color_eyre::Result;
+use colored::Colorize;
+use serde::Serialize;
+
+use crate::{
+    download::AllowedFormats,
+    output,
+    resolve::{self, Problem, ResolvedTrack},
+};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Availability {
+    Downloadable,
+    /// Replaced by an alternative that is available in the country
+    Relinked,
+    /// Restricted in the country, with no available alternative
+    UnavailableInMarket,
+    /// Not available in any of the formats allowed by the encoding profile
+    NoSuitableFormat,
+}
+
+impl Availability {
+    const ALL: [Self; 4] = [
+        Self::Downloadable,
+        Self::Relinked,
+        Self::UnavailableInMarket,
+        Self::NoSuitableFormat,
+    ];
+
+    pub fn is_downloadable(self) -> bool {
+        matches!(self, Self::Downloadable | Self::Relinked)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Downloadable => "downloadable",
+            Self::Relinked => "relinked",
+            Self::UnavailableInMarket => "unavailable in market",
+            Self::NoSuitableFormat => "no suitable format",
+        }
+    }
+}
+
+/// Classifies every track, in the same order as `tracks`.
+pub fn check(
+    tracks: &[ResolvedTrack],
+    allowed_formats: AllowedFormats,
+    country: &str,
+) -> Vec<Availability> {
+    tracks
+        .iter()
+        .map(|resolved| {
+            let media = &resolved.media;
+            if !resolve::is_allowed_in(media.restrictions(), country) {
+                Availability::UnavailableInMarket
+            } else if allowed_formats.select(media).is_none() {
+                Availability::NoSuitableFormat
+            } else if resolved.relinked_from.is_some() {
+                Availability::Relinked
+            } else {
+                Availability::Downloadable
+            }
+        })
+        .collect()
+}
+
+/// Prints the number of tracks in each class and lists the tracks that can't be downloaded.
+pub fn print_summary(tracks: &[ResolvedTrack], availability: &[Availability]) {
+    let counts: Vec<String> = Availability::ALL
+        .iter()
+        .filter_map(|&class| {
+            let count = availability.iter().filter(|&&a| a == class).count();
+            (count > 0).then(|| format!("{count} {}", class.description()))
+        })
+        .collect();
+    output::status!("{} {}", "Preflight:".bright_cyan(), counts.join(", "));
+
+    for (resolved, class) in tracks.iter().zip(availability) {
+        if !class.is_downloadable() {
+            output::status!(
+                "  {} {}. {} ({}): {}",
+                "-".bright_yellow(),
+                resolved.position,
+                resolved.media.name(),
+                resolved.media.id(),
+                class.description().bright_yellow()
+            );
+        }
+    }
+}
+
+/// Lists the items of the album or playlist that were left out while resolving it.
+pub fn print_problems(problems: &[Problem]) {
+    if problems.is_empty() {
+        return;
+    }
+    output::status!(
+        "{} {}",
+        problems.len(),
+        "items were left out:".bright_yellow()
+    );
+    for problem in problems {
+        output::status!(
+            "  {} {}. {}: {}",
+            "-".bright_yellow(),
+            problem.position,
+            problem.item,
+            problem.reason.bright_yellow()
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    country: &'a str,
+    tracks: Vec<ReportEntry<'a>>,
+    /// Items that were left out while resolving
+    problems: Vec<ProblemEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct ProblemEntry<'a> {
+    position: usize,
+    item: &'a str,
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    position: usize,
+    track_id: String,
+    title: &'a str,
+    status: Availability,
+    relinked_from: Option<String>,
+}
+
+/// Writes the preflight results as JSON.
+pub fn write_report(
+    path: &Path,
+    country: &str,
+    tracks: &[ResolvedTrack],
+    availability: &[Availability],
+    problems: &[Problem],
+) -> Result<()> {
+    let mut entries = Vec::with_capacity(tracks.len());
+    for (resolved, &status) in tracks.iter().zip(availability) {
+        entries.push(ReportEntry {
+            position: resolved.position,
+            track_id: resolved.media.id().to_base62()?,
+            title: resolved.media.name(),
+            status,
+            relinked_from: match &resolved.relinked_from {
+                Some(original) => Some(original.id.to_base62()?),
+                None => None,
+            },
+        });
+    }
+    let report = Report {
+        country,
+        tracks: entries,
+        problems: problems
+            .iter()
+            .map(|p| ProblemEntry {
+                position: p.position,
+                item: &p.item,
+                reason: &p.reason,
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &report)?;
+    Ok(())
+}
@@ -1,43 +1,355 @@
-use std::time::Duration;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use color_eyre::{eyre::bail, Result};
+use color_eyre::Result;
 use indicatif::ProgressBar;
 use librespot::{
-    core::{error::ErrorKind, Session, SpotifyId},
-    metadata::{Album, Metadata, Playlist, Track},
+    core::{date::Date, error::ErrorKind, spotify_id::SpotifyItemType, Session, SpotifyId},
+    metadata::{
+        audio::AudioFiles,
+        external_id::ExternalIds,
+        image::Images,
+        restriction::{Restriction, Restrictions},
+        Album, Episode, Metadata, Playlist, Track,
+    },
+    protocol::playlist4_external::SelectedListContent,
 };
+use percent_encoding::percent_decode_str;
+use protobuf::Message;
 
-async fn get_track(session: &Session, id: &SpotifyId) -> Result<Track> {
+use crate::{
+    error::{self, DownloadError},
+    events::{self, Event},
+};
+
+pub struct Resolved {
+    pub tracks: Vec<ResolvedTrack>,
+    pub playlist: Option<PlaylistInfo>,
+    /// Items that were left out because they can't be downloaded or their metadata
+    /// couldn't be fetched
+    pub problems: Vec<Problem>,
+}
+
+/// A track or podcast episode
+#[allow(clippy::large_enum_variant)]
+pub enum Media {
+    /// A track and its full album, which is shared by all tracks of the album. The album
+    /// embedded in the track only has the most basic metadata.
+    Track(Track, Arc<Album>),
+    Episode(Episode),
+}
+
+impl Media {
+    pub fn id(&self) -> SpotifyId {
+        match self {
+            Self::Track(track, _) => track.id,
+            Self::Episode(episode) => episode.id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Track(track, _) => &track.name,
+            Self::Episode(episode) => &episode.name,
+        }
+    }
+
+    /// The first artist of a track, or the show of an episode
+    pub fn artist(&self) -> &str {
+        match self {
+            Self::Track(track, _) => track.artists.first().map_or("", |a| a.name.as_str()),
+            Self::Episode(episode) => &episode.show_name,
+        }
+    }
+
+    pub fn files(&self) -> &AudioFiles {
+        match self {
+            Self::Track(track, _) => &track.files,
+            Self::Episode(episode) => &episode.audio,
+        }
+    }
+
+    pub fn restrictions(&self) -> &Restrictions {
+        match self {
+            Self::Track(track, _) => &track.restrictions,
+            Self::Episode(episode) => &episode.restrictions,
+        }
+    }
+
+    pub fn duration_ms(&self) -> i32 {
+        match self {
+            Self::Track(track, _) => track.duration,
+            Self::Episode(episode) => episode.duration,
+        }
+    }
+
+    /// Album covers of a track, or the episode image
+    pub fn covers(&self) -> &Images {
+        match self {
+            Self::Track(_, album) => &album.covers,
+            Self::Episode(episode) => &episode.covers,
+        }
+    }
+
+    pub fn as_track(&self) -> Option<(&Track, &Album)> {
+        match self {
+            Self::Track(track, album) => Some((track, album)),
+            Self::Episode(_) => None,
+        }
+    }
+}
+
+pub struct ResolvedTrack {
+    pub media: Media,
+    /// Position in the album or playlist, starting at 1
+    pub position: usize,
+    /// When the track was added to the playlist that is being downloaded
+    pub added_at: Option<Date>,
+    /// The original track, if it isn't available in the account's country and was
+    /// replaced by one of its alternatives
+    pub relinked_from: Option<Track>,
+}
+
+pub struct PlaylistInfo {
+    pub name: String,
+    pub owner: String,
+}
+
+/// An item of an album or playlist that was left out
+pub struct Problem {
+    /// Position of the item in the album or playlist, starting at 1
+    pub position: usize,
+    /// The item's URI, or artist and title of a local file
+    pub item: String,
+    pub reason: String,
+    /// Kind of the error that caused it, `None` if the item can't be downloaded at all
+    pub kind: Option<&'static str>,
+    pub retryable: bool,
+}
+
+/// An item of an album or playlist that should be resolved
+struct Item {
+    id: SpotifyId,
+    added_at: Option<Date>,
+    /// The raw URI of a local file, which isn't kept in its `SpotifyId`
+    local_uri: Option<String>,
+}
+
+async fn get_metadata<T: Metadata>(session: &Session, id: &SpotifyId) -> Result<T, DownloadError> {
     loop {
-        match Track::get(session, id).await {
+        match T::get(session, id).await {
             Err(e) if e.kind == ErrorKind::ResourceExhausted => {
                 tokio::time::sleep(Duration::from_secs(10)).await;
             }
-            Err(e) => bail!(e),
+            Err(e) => return Err(DownloadError::Metadata(e)),
             Ok(o) => return Ok(o),
         }
     }
 }
 
-async fn resolve_track(session: &Session, id: &SpotifyId) -> Result<Track> {
-    let track = get_track(session, id).await?;
-    if let Some(alternative) = track.alternatives.first() {
-        Ok(get_track(session, alternative).await?)
-    } else {
-        Ok(track)
+fn restriction_allows(restriction: &Restriction, country: &str) -> bool {
+    if let Some(allowed) = &restriction.countries_allowed {
+        if !allowed.iter().any(|c| c == country) {
+            return false;
+        }
+    }
+    if let Some(forbidden) = &restriction.countries_forbidden {
+        if forbidden.iter().any(|c| c == country) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Whether a track or episode with these restrictions can be played in the given country.
+pub fn is_allowed_in(restrictions: &Restrictions, country: &str) -> bool {
+    restrictions.iter().all(|r| restriction_allows(r, country))
+}
+
+/// Whether the track can be streamed in the given country.
+pub fn is_available(track: &Track, country: &str) -> bool {
+    !track.files.is_empty() && is_allowed_in(&track.restrictions, country)
+}
+
+/// Finds an ID like the ISRC of a track or the UPC of an album.
+pub fn external_id<'a>(ids: &'a ExternalIds, external_type: &str) -> Option<&'a str> {
+    ids.iter()
+        .find(|i| i.external_type == external_type)
+        .map(|i| i.id.as_str())
+}
+
+fn isrc(track: &Track) -> Option<&str> {
+    external_id(&track.external_ids, "isrc")
+}
+
+/// Picks the alternative to use for a track that isn't available in the country. Alternatives
+/// from the same album are preferred, then ones with the same ISRC (the same recording).
+async fn find_relink(
+    session: &Session,
+    track: &Track,
+    country: &str,
+) -> Result<Option<Track>, DownloadError> {
+    let mut available = Vec::new();
+    for alternative in track.alternatives.iter() {
+        let alternative = get_metadata::<Track>(session, alternative).await?;
+        if is_available(&alternative, country) {
+            available.push(alternative);
+        }
     }
+
+    let same_album = available.iter().position(|a| a.album.id == track.album.id);
+    let same_isrc = || {
+        let isrc = isrc(track)?;
+        available.iter().position(|a| self::isrc(a) == Some(isrc))
+    };
+    let best = same_album.or_else(same_isrc).unwrap_or(0);
+    Ok((best < available.len()).then(|| available.swap_remove(best)))
 }
 
-async fn resolve_track_ids(
+/// Fetches the track, relinks it to an alternative if it isn't available in the account's
+/// country, and looks up the full album, which also includes the track listing.
+async fn resolve_track(
     session: &Session,
-    ids: impl Iterator<Item = &SpotifyId>,
+    id: &SpotifyId,
+    albums: &mut HashMap<SpotifyId, Arc<Album>>,
+    added_at: Option<Date>,
+) -> Result<ResolvedTrack, DownloadError> {
+    let original = get_metadata::<Track>(session, id).await?;
+    let country = session.country();
+    let relink = if is_available(&original, &country) {
+        None
+    } else {
+        find_relink(session, &original, &country).await?
+    };
+    let (track, relinked_from) = match relink {
+        Some(alternative) => (alternative, Some(original)),
+        None => (original, None),
+    };
+
+    let album = match albums.get(&track.album.id) {
+        Some(album) => album.clone(),
+        None => {
+            let album = Arc::new(get_metadata::<Album>(session, &track.album.id).await?);
+            albums.insert(album.id, album.clone());
+            album
+        }
+    };
+
+    Ok(ResolvedTrack {
+        media: Media::Track(track, album),
+        position: 1,
+        added_at,
+        relinked_from,
+    })
+}
+
+async fn resolve_episode(
+    session: &Session,
+    id: &SpotifyId,
+    added_at: Option<Date>,
+) -> Result<ResolvedTrack, DownloadError> {
+    Ok(ResolvedTrack {
+        media: Media::Episode(get_metadata::<Episode>(session, id).await?),
+        position: 1,
+        added_at,
+        relinked_from: None,
+    })
+}
+
+/// Formats a local file URI (`spotify:local:<artist>:<album>:<title>:<duration>`)
+/// as "Artist - Title".
+fn describe_local_file(uri: &str) -> Option<String> {
+    let mut parts = uri.strip_prefix("spotify:local:")?.split(':');
+    let mut decode = || {
+        let part = parts.next()?.replace('+', " ");
+        Some(percent_decode_str(&part).decode_utf8_lossy().into_owned())
+    };
+    let artist = decode()?;
+    let _album = decode()?;
+    let title = decode()?;
+    Some(match (artist.is_empty(), title.is_empty()) {
+        (_, true) => return None,
+        (true, false) => title,
+        (false, false) => format!("{artist} - {title}"),
+    })
+}
+
+/// Resolves the items one by one. Items that can't be resolved are recorded as problems
+/// instead of failing the whole resource.
+async fn resolve_items(
+    session: &Session,
+    items: Vec<Item>,
+    mut albums: HashMap<SpotifyId, Arc<Album>>,
     pb: ProgressBar,
-) -> Result<Vec<Track>> {
+) -> (Vec<ResolvedTrack>, Vec<Problem>) {
     let mut tracks = Vec::new();
-    for id in pb.wrap_iter(ids) {
-        tracks.push(resolve_track(session, id).await?);
+    let mut problems = Vec::new();
+    let total = items.len();
+    for (n, item) in pb.wrap_iter(items.into_iter().enumerate()) {
+        events::emit(Event::ResolveProgress { resolved: n, total });
+        let result = match item.id.item_type {
+            SpotifyItemType::Track => {
+                resolve_track(session, &item.id, &mut albums, item.added_at).await
+            }
+            SpotifyItemType::Episode => resolve_episode(session, &item.id, item.added_at).await,
+            SpotifyItemType::Local => {
+                problems.push(Problem {
+                    position: n + 1,
+                    item: item
+                        .local_uri
+                        .as_deref()
+                        .and_then(describe_local_file)
+                        .unwrap_or_else(|| "Unknown local file".into()),
+                    reason: "local files are not downloadable".into(),
+                    kind: None,
+                    retryable: false,
+                });
+                continue;
+            }
+            item_type => {
+                problems.push(Problem {
+                    position: n + 1,
+                    item: item
+                        .id
+                        .to_uri()
+                        .unwrap_or_else(|_| format!("{:?}", item.id)),
+                    reason: format!("{item_type:?} items are not downloadable"),
+                    kind: None,
+                    retryable: false,
+                });
+                continue;
+            }
+        };
+        match result {
+            Ok(track) => tracks.push(ResolvedTrack {
+                position: n + 1,
+                ..track
+            }),
+            Err(e) => problems.push(Problem {
+                position: n + 1,
+                item: item
+                    .id
+                    .to_uri()
+                    .unwrap_or_else(|_| format!("{:?}", item.id)),
+                reason: error::causes(&e).join(": "),
+                kind: Some(e.kind()),
+                retryable: e.is_retryable(),
+            }),
+        }
     }
-    Ok(tracks)
+    (tracks, problems)
+}
+
+/// Fetches the URIs of all playlist items as they are stored, which unlike the parsed
+/// metadata still contain the artist and title of local files.
+async fn playlist_item_uris(session: &Session, id: &SpotifyId) -> Result<Vec<String>> {
+    let response = session.spclient().get_playlist(id).await?;
+    let content = SelectedListContent::parse_from_bytes(&response)?;
+    Ok(content
+        .contents
+        .items
+        .iter()
+        .map(|item| item.uri().to_string())
+        .collect())
 }
 
 pub async fn resolve_tracks(
@@ -45,24 +357,79 @@ pub async fn resolve_tracks(
     resource_id: &str,
     session: &Session,
     pb: ProgressBar,
-) -> Result<Vec<Track>> {
+) -> Result<Resolved> {
     let id = SpotifyId::from_base62(resource_id)?;
     match resource_type {
         "track" => {
             pb.set_length(1);
-            let track = resolve_track(session, &id).await?;
+            let track = resolve_track(session, &id, &mut HashMap::new(), None).await?;
+            pb.finish_using_style();
+            Ok(Resolved {
+                tracks: vec![track],
+                playlist: None,
+                problems: Vec::new(),
+            })
+        }
+        "episode" => {
+            pb.set_length(1);
+            let episode = resolve_episode(session, &id, None).await?;
             pb.finish_using_style();
-            Ok(vec![track])
+            Ok(Resolved {
+                tracks: vec![episode],
+                playlist: None,
+                problems: Vec::new(),
+            })
         }
         "album" => {
-            let album = Album::get(session, &id).await?;
-            pb.set_length(album.tracks().count() as u64);
-            Ok(resolve_track_ids(session, album.tracks(), pb).await?)
+            let album = get_metadata::<Album>(session, &id).await?;
+            let items: Vec<Item> = album
+                .tracks()
+                .map(|&id| Item {
+                    id,
+                    added_at: None,
+                    local_uri: None,
+                })
+                .collect();
+            pb.set_length(items.len() as u64);
+            let albums = HashMap::from([(album.id, Arc::new(album))]);
+            let (tracks, problems) = resolve_items(session, items, albums, pb).await;
+            Ok(Resolved {
+                tracks,
+                playlist: None,
+                problems,
+            })
         }
         "playlist" => {
-            let playlist = Playlist::get(session, &id).await?;
-            pb.set_length(playlist.tracks().count() as u64);
-            Ok(resolve_track_ids(session, playlist.tracks(), pb).await?)
+            let playlist = get_metadata::<Playlist>(session, &id).await?;
+            let items = &playlist.contents.items;
+            let uris = if items
+                .iter()
+                .any(|item| item.id.item_type == SpotifyItemType::Local)
+            {
+                // only used for describing local files, so a failure isn't fatal
+                playlist_item_uris(session, &id).await.unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            let items: Vec<Item> = items
+                .iter()
+                .enumerate()
+                .map(|(n, item)| Item {
+                    id: item.id,
+                    added_at: Some(item.attributes.timestamp),
+                    local_uri: uris.get(n).cloned(),
+                })
+                .collect();
+            pb.set_length(items.len() as u64);
+            let (tracks, problems) = resolve_items(session, items, HashMap::new(), pb).await;
+            Ok(Resolved {
+                tracks,
+                playlist: Some(PlaylistInfo {
+                    name: playlist.name().to_string(),
+                    owner: playlist.id.username.clone(),
+                }),
+                problems,
+            })
         }
         _ => panic!("Unknown resource type {resource_type:?}. The regex shouldn't have matched."),
     }
@@ -1,12 +1,19 @@
 use color_eyre::{eyre::bail, Result};
 use std::{borrow::Cow, fmt::Write};
 
+use crate::filename::SanitizeConfig;
+
 #[derive(Debug)]
 pub struct Template(Vec<Component>);
 
 #[derive(Debug)]
 enum Component {
     Literal(String),
+    Field(Field, Spec),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
     Artists,
     Title,
     Album,
@@ -16,8 +23,127 @@ enum Component {
     Language,
     Year,
     Publisher,
+    Artist,
+    ArtistsFeat,
+    AlbumArtist,
+    AlbumArtists,
+    Isrc,
+    Upc,
+    TotalTracks,
+    TotalDiscs,
+    ReleaseDate,
+    Duration,
+    DurationMs,
+    Explicit,
+    Popularity,
+    AlbumType,
+    Copyright,
+    PhonographicCopyright,
+    TrackId,
+    TrackUri,
+    AlbumId,
+    AlbumUri,
+    ArtistId,
+    PlaylistName,
+    PlaylistOwner,
+    AddedAt,
+    SourceFormat,
+}
+
+impl Field {
+    fn from_code(code: u8) -> Option<Self> {
+        Some(match code {
+            b'a' => Self::Artists,
+            b't' => Self::Title,
+            b'b' => Self::Album,
+            b's' => Self::Seq,
+            b'n' => Self::Track,
+            b'd' => Self::Disc,
+            b'l' => Self::Language,
+            b'y' => Self::Year,
+            b'p' => Self::Publisher,
+            _ => return None,
+        })
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "artists" => Self::Artists,
+            "title" => Self::Title,
+            "album" => Self::Album,
+            "seq" => Self::Seq,
+            "track_number" => Self::Track,
+            "disc_number" => Self::Disc,
+            "language" => Self::Language,
+            "year" => Self::Year,
+            "publisher" => Self::Publisher,
+            "artist" => Self::Artist,
+            "artists_feat" => Self::ArtistsFeat,
+            "album_artist" => Self::AlbumArtist,
+            "album_artists" => Self::AlbumArtists,
+            "isrc" => Self::Isrc,
+            "upc" => Self::Upc,
+            "total_tracks" => Self::TotalTracks,
+            "total_discs" => Self::TotalDiscs,
+            "release_date" => Self::ReleaseDate,
+            "duration" => Self::Duration,
+            "duration_ms" => Self::DurationMs,
+            "explicit" => Self::Explicit,
+            "popularity" => Self::Popularity,
+            "album_type" => Self::AlbumType,
+            "copyright" => Self::Copyright,
+            "phonographic_copyright" => Self::PhonographicCopyright,
+            "track_id" => Self::TrackId,
+            "track_uri" => Self::TrackUri,
+            "album_id" => Self::AlbumId,
+            "album_uri" => Self::AlbumUri,
+            "artist_id" => Self::ArtistId,
+            "playlist_name" => Self::PlaylistName,
+            "playlist_owner" => Self::PlaylistOwner,
+            "added_at" => Self::AddedAt,
+            "source_format" => Self::SourceFormat,
+            _ => return None,
+        })
+    }
 }
 
+/// Formatting options of a placeholder, i.e. the part after `:` in `{field:spec}`.
+///
+/// The syntax is `[0][width][.max]`: `width` pads the value to at least that many characters
+/// (with zeros if the spec starts with `0`), `max` cuts text values down to that many characters.
+#[derive(Debug, Default)]
+struct Spec {
+    zero_pad: bool,
+    width: Option<usize>,
+    max_chars: Option<usize>,
+}
+
+impl Spec {
+    fn parse(spec: &str) -> Option<Self> {
+        let (width, max_chars) = match spec.split_once('.') {
+            Some((width, max)) => (width, Some(max.parse().ok()?)),
+            None => (spec, None),
+        };
+        let zero_pad = width.len() > 1 && width.starts_with('0');
+        let width = if width.is_empty() {
+            None
+        } else {
+            Some(width.parse().ok()?)
+        };
+        Some(Self {
+            zero_pad,
+            width,
+            max_chars,
+        })
+    }
+}
+
+enum Value<'a> {
+    Text(&'a str),
+    Number(i64),
+}
+
+#[derive(Default)]
 pub struct Fields<'a> {
     pub artists: Cow<'a, str>,
     pub title: Cow<'a, str>,
@@ -29,10 +155,44 @@ pub struct Fields<'a> {
     pub language: Cow<'a, str>,
     pub year: i32,
     pub publisher: Cow<'a, str>,
+    /// The first (primary) artist of the track
+    pub artist: Cow<'a, str>,
+    /// Artists of the track in the "Main feat. Featured" format
+    pub artists_feat: Cow<'a, str>,
+    /// The first (primary) artist of the album
+    pub album_artist: Cow<'a, str>,
+    pub album_artists: Cow<'a, str>,
+    pub isrc: Cow<'a, str>,
+    pub upc: Cow<'a, str>,
+    /// Number of tracks on the disc of the track
+    pub total_tracks: usize,
+    pub total_discs: usize,
+    /// Album release date in the `YYYY-MM-DD` format
+    pub release_date: Cow<'a, str>,
+    /// Track duration in the `M:SS` format
+    pub duration: Cow<'a, str>,
+    pub duration_ms: i32,
+    pub explicit: bool,
+    pub popularity: i32,
+    pub album_type: Cow<'a, str>,
+    pub copyright: Cow<'a, str>,
+    pub phonographic_copyright: Cow<'a, str>,
+    pub track_id: Cow<'a, str>,
+    pub track_uri: Cow<'a, str>,
+    pub album_id: Cow<'a, str>,
+    pub album_uri: Cow<'a, str>,
+    pub artist_id: Cow<'a, str>,
+    pub playlist_name: Cow<'a, str>,
+    pub playlist_owner: Cow<'a, str>,
+    /// Date when the track was added to the playlist in the `YYYY-MM-DD` format
+    pub added_at: Cow<'a, str>,
+    /// Spotify audio format that is downloaded, e.g. `OGG_VORBIS_320`
+    pub source_format: Cow<'a, str>,
 }
 
 impl<'a> Fields<'a> {
-    pub fn sanitize_path(&'a self) -> Self {
+    pub fn sanitize_path(&'a self, sanitizer: &SanitizeConfig) -> Self {
+        let sanitize_path = |value: &'a str| sanitizer.sanitize(value);
         Fields {
             artists: sanitize_path(&self.artists),
             title: sanitize_path(&self.title),
@@ -44,65 +204,131 @@ impl<'a> Fields<'a> {
             language: sanitize_path(&self.language),
             year: self.year,
             publisher: sanitize_path(&self.publisher),
+            artist: sanitize_path(&self.artist),
+            artists_feat: sanitize_path(&self.artists_feat),
+            album_artist: sanitize_path(&self.album_artist),
+            album_artists: sanitize_path(&self.album_artists),
+            isrc: sanitize_path(&self.isrc),
+            upc: sanitize_path(&self.upc),
+            total_tracks: self.total_tracks,
+            total_discs: self.total_discs,
+            release_date: sanitize_path(&self.release_date),
+            duration: sanitize_path(&self.duration),
+            duration_ms: self.duration_ms,
+            explicit: self.explicit,
+            popularity: self.popularity,
+            album_type: sanitize_path(&self.album_type),
+            copyright: sanitize_path(&self.copyright),
+            phonographic_copyright: sanitize_path(&self.phonographic_copyright),
+            track_id: sanitize_path(&self.track_id),
+            track_uri: sanitize_path(&self.track_uri),
+            album_id: sanitize_path(&self.album_id),
+            album_uri: sanitize_path(&self.album_uri),
+            artist_id: sanitize_path(&self.artist_id),
+            playlist_name: sanitize_path(&self.playlist_name),
+            playlist_owner: sanitize_path(&self.playlist_owner),
+            added_at: sanitize_path(&self.added_at),
+            source_format: sanitize_path(&self.source_format),
         }
     }
-}
 
-#[cfg(unix)]
-fn sanitize_pattern(c: char) -> bool {
-    c == '/'
-}
-
-#[cfg(windows)]
-fn sanitize_pattern(c: char) -> bool {
-    c == '/'
-        || c == '\\'
-        || c == ':'
-        || c == '*'
-        || c == '?'
-        || c == '"'
-        || c == '<'
-        || c == '>'
-        || c == '|'
-}
-
-fn sanitize_path(path: &str) -> Cow<'_, str> {
-    if path.contains(sanitize_pattern) {
-        path.replace(sanitize_pattern, " ").into()
-    } else {
-        path.into()
+    fn get(&self, field: Field) -> Value<'_> {
+        match field {
+            Field::Artists => Value::Text(&self.artists),
+            Field::Title => Value::Text(&self.title),
+            Field::Album => Value::Text(&self.album),
+            Field::Seq => Value::Number(self.seq as i64),
+            Field::Track => Value::Number(self.track.into()),
+            Field::Disc => Value::Number(self.disc.into()),
+            Field::Language => Value::Text(&self.language),
+            Field::Year => Value::Number(self.year.into()),
+            Field::Publisher => Value::Text(&self.publisher),
+            Field::Artist => Value::Text(&self.artist),
+            Field::ArtistsFeat => Value::Text(&self.artists_feat),
+            Field::AlbumArtist => Value::Text(&self.album_artist),
+            Field::AlbumArtists => Value::Text(&self.album_artists),
+            Field::Isrc => Value::Text(&self.isrc),
+            Field::Upc => Value::Text(&self.upc),
+            Field::TotalTracks => Value::Number(self.total_tracks as i64),
+            Field::TotalDiscs => Value::Number(self.total_discs as i64),
+            Field::ReleaseDate => Value::Text(&self.release_date),
+            Field::Duration => Value::Text(&self.duration),
+            Field::DurationMs => Value::Number(self.duration_ms.into()),
+            Field::Explicit => Value::Number(self.explicit.into()),
+            Field::Popularity => Value::Number(self.popularity.into()),
+            Field::AlbumType => Value::Text(&self.album_type),
+            Field::Copyright => Value::Text(&self.copyright),
+            Field::PhonographicCopyright => Value::Text(&self.phonographic_copyright),
+            Field::TrackId => Value::Text(&self.track_id),
+            Field::TrackUri => Value::Text(&self.track_uri),
+            Field::AlbumId => Value::Text(&self.album_id),
+            Field::AlbumUri => Value::Text(&self.album_uri),
+            Field::ArtistId => Value::Text(&self.artist_id),
+            Field::PlaylistName => Value::Text(&self.playlist_name),
+            Field::PlaylistOwner => Value::Text(&self.playlist_owner),
+            Field::AddedAt => Value::Text(&self.added_at),
+            Field::SourceFormat => Value::Text(&self.source_format),
+        }
     }
 }
 
 impl Template {
+    /// Compiles a template made of literal text, `%x` wildcards and `{field}`/`{field:spec}`
+    /// placeholders. `%%` can be used for a literal `%`, braces are only special if they
+    /// enclose the name of a field.
     pub fn compile(template: &str) -> Result<Self> {
-        let mut prev_pos = 0;
         let mut components: Vec<Component> = Vec::new();
-        let template_bytes = template.as_bytes();
-        for (pos, byte) in template_bytes.iter().enumerate() {
-            if *byte == b'%' {
-                let literal = &template[prev_pos..pos];
-                if !literal.is_empty() {
-                    components.push(Component::Literal(literal.to_string()));
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '%' => {
+                    let Some((_, code)) = chars.next() else {
+                        bail!("{template:?} is not a valid path template.");
+                    };
+                    if code == '%' {
+                        literal.push('%');
+                        continue;
+                    }
+                    let Some(field) = u8::try_from(code).ok().and_then(Field::from_code) else {
+                        bail!("{template:?} is not a valid path template.");
+                    };
+                    if !literal.is_empty() {
+                        components.push(Component::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(Component::Field(field, Spec::default()));
                 }
-                prev_pos = pos + 2;
-                match template_bytes.get(pos + 1) {
-                    Some(b'a') => components.push(Component::Artists),
-                    Some(b't') => components.push(Component::Title),
-                    Some(b'b') => components.push(Component::Album),
-                    Some(b's') => components.push(Component::Seq),
-                    Some(b'n') => components.push(Component::Track),
-                    Some(b'd') => components.push(Component::Disc),
-                    Some(b'l') => components.push(Component::Language),
-                    Some(b'y') => components.push(Component::Year),
-                    Some(b'p') => components.push(Component::Publisher),
-                    _ => bail!("{template:?} is not a valid path template."),
+                '{' => {
+                    // braces that don't enclose a known field are kept as they are
+                    let Some(len) = template[pos + 1..].find('}') else {
+                        literal.push('{');
+                        continue;
+                    };
+                    let placeholder = &template[pos + 1..pos + 1 + len];
+                    let (name, spec) = match placeholder.split_once(':') {
+                        Some((name, spec)) => (name, Spec::parse(spec)),
+                        None => (placeholder, Some(Spec::default())),
+                    };
+                    let Some(field) = Field::from_name(name) else {
+                        literal.push('{');
+                        continue;
+                    };
+                    let Some(spec) = spec else {
+                        bail!(
+                            "{template:?} is not a valid path template: invalid format of {name:?}"
+                        );
+                    };
+                    if !literal.is_empty() {
+                        components.push(Component::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(Component::Field(field, spec));
+                    while chars.next_if(|&(p, _)| p <= pos + 1 + len).is_some() {}
                 }
+                c => literal.push(c),
             }
         }
-        let remainder = &template[prev_pos..];
-        if !remainder.is_empty() {
-            components.push(Component::Literal(remainder.to_string()));
+        if !literal.is_empty() {
+            components.push(Component::Literal(literal));
         }
         Ok(Self(components))
     }
@@ -112,20 +338,64 @@ impl Template {
         for component in &self.0 {
             match component {
                 Component::Literal(l) => output.push_str(l),
-                Component::Artists => output.push_str(&fields.artists),
-                Component::Title => output.push_str(&fields.title),
-                Component::Album => output.push_str(&fields.album),
-                Component::Seq => {
-                    let (seq, seq_digits) = (fields.seq, fields.seq_digits);
-                    write!(output, "{seq:0seq_digits$}")?;
+                Component::Field(field, spec) => {
+                    let (zero_pad, width) = match (field, spec.width) {
+                        (Field::Seq, None) => (true, fields.seq_digits),
+                        (_, width) => (spec.zero_pad, width.unwrap_or(0)),
+                    };
+                    match fields.get(*field) {
+                        Value::Text(text) => {
+                            let text = match spec.max_chars {
+                                Some(max) => text.chars().take(max).collect(),
+                                None => Cow::Borrowed(text),
+                            };
+                            write!(output, "{text:width$}")?;
+                        }
+                        Value::Number(n) if zero_pad => write!(output, "{n:0width$}")?,
+                        Value::Number(n) => write!(output, "{n:width$}")?,
+                    }
                 }
-                Component::Track => write!(output, "{}", fields.track)?,
-                Component::Disc => write!(output, "{}", fields.disc)?,
-                Component::Language => output.push_str(&fields.language),
-                Component::Year => write!(output, "{}", fields.year)?,
-                Component::Publisher => output.push_str(&fields.publisher),
             };
         }
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> String {
+        let fields = Fields {
+            title: "Song".into(),
+            track: 3,
+            ..Default::default()
+        };
+        Template::compile(template)
+            .unwrap()
+            .resolve(&fields)
+            .unwrap()
+    }
+
+    #[test]
+    fn resolves_known_fields() {
+        assert_eq!(render("{track_number:02} {title}"), "03 Song");
+        assert_eq!(render("%% {track_number}"), "% 3");
+    }
+
+    #[test]
+    fn keeps_other_braces() {
+        assert_eq!(render("{live} {title}"), "{live} Song");
+        assert_eq!(render("}{title}"), "}Song");
+        assert_eq!(render("a{b{title}"), "a{bSong");
+        assert_eq!(render("{title"), "{title");
+        // doubled braces are not escapes
+        assert_eq!(render("{{%t}}"), "{{Song}}");
+        assert_eq!(render("{{title}}"), "{Song}");
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        assert!(Template::compile("{title:x}").is_err());
+    }
+}